@@ -1,14 +1,29 @@
-use crate::utils::deinterleave;
+use crate::utils::{apply_gain_ramp, db_to_gain, deinterleave, interleave};
 use druid::piet::GradientStop;
 use druid::Color;
-use druid::{kurbo::BezPath, LinearGradient, UnitPoint};
-use hound::{SampleFormat, WavReader};
+use druid::{
+    kurbo::{BezPath, PathEl},
+    LinearGradient, UnitPoint,
+};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
 use rustfft::{num_complex::Complex, FftPlanner};
+use std::path::Path;
 pub struct Peaks {
     pub peaks: Vec<(usize, (BezPath, LinearGradient))>,
 }
 
+impl Peaks {
+    /// the approximate number of bytes held by the waveform paths
+    pub fn memory_usage(&self) -> usize {
+        self.peaks
+            .iter()
+            .map(|(_, (path, _))| path.elements().len() * std::mem::size_of::<PathEl>())
+            .sum()
+    }
+}
+
 /// An audio file, loaded into memory
+#[derive(Clone)]
 pub struct AudioFile {
     /// The sample data
     pub data: Vec<f32>,
@@ -89,6 +104,7 @@ impl AudioFile {
     }
 
     pub fn spectral_peaks(&self, channel: usize) -> Peaks {
+        span!("spectral peaks");
         Peaks {
             peaks: (&[128, 256, 512, 1024, 2048, 4096])
                 .iter()
@@ -105,7 +121,68 @@ impl AudioFile {
         &self.data[start..(start + self.num_samples)]
     }
 
+    /// the approximate number of bytes held by the sample data
+    pub fn memory_usage(&self) -> usize {
+        self.data.capacity() * std::mem::size_of::<f32>()
+    }
+
+    /// return a mutable buffer of samples corresponding to a channel in the audio file
+    pub fn get_channel_mut(&mut self, idx: usize) -> &'_ mut [f32] {
+        debug_assert!(idx < self.num_channels);
+        let start = self.num_samples * idx;
+        &mut self.data[start..(start + self.num_samples)]
+    }
+
+    /// multiply a range of samples in every channel by a gain moving linearly from `start_gain`
+    /// to `end_gain`
+    pub fn gain_ramp(&mut self, start: usize, end: usize, start_gain: f32, end_gain: f32) {
+        debug_assert!(start <= end && end <= self.num_samples);
+        for ch in 0..self.num_channels {
+            apply_gain_ramp(
+                &mut self.get_channel_mut(ch)[start..end],
+                start_gain,
+                end_gain,
+            );
+        }
+    }
+
+    /// fade a range of samples in from silence
+    pub fn fade_in(&mut self, start: usize, end: usize) {
+        self.gain_ramp(start, end, 0.0, 1.0);
+    }
+
+    /// fade a range of samples out to silence
+    pub fn fade_out(&mut self, start: usize, end: usize) {
+        self.gain_ramp(start, end, 1.0, 0.0);
+    }
+
+    /// change the level of a range of samples, in decibels
+    pub fn apply_gain(&mut self, start: usize, end: usize, db: f32) {
+        let gain = db_to_gain(db);
+        self.gain_ramp(start, end, gain, gain);
+    }
+
+    /// copy a range of samples into a new audio file
+    pub fn slice(&self, start: usize, end: usize) -> Self {
+        debug_assert!(start <= end && end <= self.num_samples);
+        let mut data = Vec::with_capacity(self.num_channels * (end - start));
+        for ch in 0..self.num_channels {
+            data.extend_from_slice(&self.get_channel(ch)[start..end]);
+        }
+        Self {
+            data,
+            sample_rate: self.sample_rate,
+            num_channels: self.num_channels,
+            num_samples: end - start,
+        }
+    }
+
     /// plots the "stair step" pattern when the stepsize is 1.
+    ///
+    /// The paths are normalized to a unit square, where `x` is the position within `start..end`
+    /// and `y` is the amplitude mapped from `[-1, 1]` to `[1, 0]`. Above a step size of 1 the
+    /// first path spans the lowest and highest sample of each step and the second spans
+    /// plus and minus its RMS level, both on the same scale.
     pub fn plot(
         &self,
         step_size: usize,
@@ -130,18 +207,12 @@ impl AudioFile {
 
         let len = channel.len() as f64;
         if step_size == 1 {
-            let mut x0 = 0.0;
-            let mut y0 = 0.0;
-            for (x1, y1) in (0..self.num_samples)
-                .map(|n| (n as f64) / len)
-                .zip(channel.iter().map(|f| *f as f64))
-            {
-                peak.move_to((x0, y0));
-                peak.line_to((x0, y1));
-                peak.line_to((x0, y1));
-                peak.line_to((x1, y1));
-                x0 = x1;
-                y0 = y1;
+            peak.move_to((0.0, 0.5));
+            for (n, y) in channel.iter().map(|f| *f as f64).enumerate() {
+                let x0 = (n as f64) / len;
+                let x1 = ((n + 1) as f64) / len;
+                peak.line_to((x0, 0.5 - 0.5 * y));
+                peak.line_to((x1, 0.5 - 0.5 * y));
             }
         } else {
             peak0.move_to((0.0, 0.5));
@@ -150,21 +221,21 @@ impl AudioFile {
             avg1.move_to((0.0, 0.5));
 
             for n in (0..channel.len()).step_by(step_size) {
-                let (n0, n1) = (n, (n + step_size).min(self.num_samples));
+                let (n0, n1) = (n, (n + step_size).min(channel.len()));
                 let x = (n as f64) / len;
                 let (y_min, y_peak, y_rms) = (channel[n0..n1])
                     .iter()
                     .fold((0.0f32, 0.0f32, 0.0f32), |(m, p, r), y| {
-                        (m.min(*y), p.max(*y), r + y.abs())
+                        (m.min(*y), p.max(*y), r + y * y)
                     });
                 let y_peak = y_peak as f64;
-                let y_rms = y_rms as f64;
+                let y_rms = (y_rms as f64 / ((n1 - n0) as f64)).sqrt();
                 let y_min = y_min as f64;
 
-                peak0.line_to((x, 0.5 - y_peak));
-                peak1.line_to((x, 0.5 - y_min));
-                avg0.line_to((x, 0.5 + y_rms / (step_size as f64)));
-                avg1.line_to((x, 0.5 - y_rms / (step_size as f64)));
+                peak0.line_to((x, 0.5 - 0.5 * y_peak));
+                peak1.line_to((x, 0.5 - 0.5 * y_min));
+                avg0.line_to((x, 0.5 + 0.5 * y_rms));
+                avg1.line_to((x, 0.5 - 0.5 * y_rms));
             }
 
             peak0.line_to((1.0, 0.5));
@@ -183,8 +254,26 @@ impl AudioFile {
         (peak, avg)
     }
 
+    /// write the file to disk as a 32 bit floating point .wav
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), hound::Error> {
+        let spec = WavSpec {
+            channels: self.num_channels as u16,
+            sample_rate: self.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut interleaved = vec![0.0; self.data.len()];
+        interleave(&self.data, &mut interleaved, self.num_channels);
+        let mut writer = WavWriter::create(path, spec)?;
+        for sample in interleaved {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()
+    }
+
     /// open a file
     pub fn open(path: &str) -> Result<Self, hound::Error> {
+        span!("open file");
         let mut reader = WavReader::open(path)?;
         let spec = reader.spec();
         let mut data = Vec::with_capacity((spec.channels as usize) * (reader.duration() as usize));
@@ -1,6 +1,36 @@
-use crate::utils::interleave;
-use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::Stream;
+use crate::utils::{self, apply_gain_ramp, db_to_gain};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Sample, SampleFormat, Stream, StreamError};
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// the highest level the limiter lets through, about -0.2dBFS
+const LIMIT_CEILING: f32 = 0.977;
+
+/// the level above which the limiter rounds peaks off rather than passing them through
+const LIMIT_KNEE: f32 = 0.9;
+
+/// how quickly the limiter recovers once the output is quiet again, in dB per second
+const LIMIT_RELEASE: f32 = 20.0;
+
+/// the number of channels fed to a cue output
+const CUE_CHANNELS: usize = 2;
+
+/// how hard a cue output speeds up or slows down to hold its buffer at the target
+const CUE_DRIFT_GAIN: f64 = 0.01;
+
+/// the furthest a cue output can be resampled away from its nominal rate, which is far more
+/// than the clocks of two devices ever drift apart
+const CUE_MAX_DRIFT: f64 = 0.005;
+
+/// the most blocks of input that can build up before the oldest are dropped, which bounds
+/// the monitoring latency when the input clock runs faster than the output
+const MAX_INPUT_BACKLOG: usize = 4;
 
 /// The playback context is used by the audio callback to map data from the audio
 /// file to the playback buffer.
@@ -8,56 +38,1535 @@ pub struct PlaybackContext<'a> {
     pub buffer_size: usize,
     pub sample_rate: f64,
     pub num_channels: usize,
+    /// true if the device skipped audio between the last block and this one
+    pub xrun: bool,
+    /// when the first frame of this block plays, in frames of the device clock since the
+    /// stream started
+    pub stream_time: u64,
+    /// true for the first block after the stream was rebuilt on another device
+    pub device_changed: bool,
+    /// the number of input channels, which is 0 unless the stream is duplex
+    pub num_inputs: usize,
+    /// when the device called back and when the first frame of this block will be heard,
+    /// if the host reports it
+    pub timestamp: Option<cpal::OutputStreamTimestamp>,
+    /// the frames between the callback and the first frame of this block being heard, as
+    /// reported by the device
+    pub output_latency: usize,
     output_buffer: &'a mut [f32],
+    input_buffer: &'a [f32],
 }
 
 impl<'a> PlaybackContext<'a> {
+    /// a context rendering into a buffer of deinterleaved samples, without a device
+    pub fn new(
+        buffer_size: usize,
+        sample_rate: f64,
+        num_channels: usize,
+        output_buffer: &'a mut [f32],
+    ) -> Self {
+        Self {
+            buffer_size,
+            sample_rate,
+            num_channels,
+            xrun: false,
+            stream_time: 0,
+            device_changed: false,
+            num_inputs: 0,
+            timestamp: None,
+            output_latency: 0,
+            output_buffer,
+            input_buffer: &[],
+        }
+    }
+
+    /// a context for the same block, borrowing the buffers of this one so that it can be
+    /// read again after a callback has rendered into it
+    fn reborrow(&mut self) -> PlaybackContext<'_> {
+        PlaybackContext {
+            buffer_size: self.buffer_size,
+            sample_rate: self.sample_rate,
+            num_channels: self.num_channels,
+            xrun: self.xrun,
+            stream_time: self.stream_time,
+            device_changed: self.device_changed,
+            num_inputs: self.num_inputs,
+            timestamp: self.timestamp,
+            output_latency: self.output_latency,
+            output_buffer: &mut *self.output_buffer,
+            input_buffer: self.input_buffer,
+        }
+    }
+
     /// return a buffer of output samples corresponding to a channel index
     pub fn get_output(&mut self, idx: usize) -> &'_ mut [f32] {
         let offset = idx * self.buffer_size;
         &mut self.output_buffer[offset..offset + self.buffer_size]
     }
+
+    /// return the input captured for this block on a channel index, if the stream is duplex.
+    /// Frames the input didn't deliver in time are silent.
+    pub fn get_input(&self, idx: usize) -> Option<&'_ [f32]> {
+        if idx >= self.num_inputs {
+            return None;
+        }
+        let offset = idx * self.buffer_size;
+        Some(&self.input_buffer[offset..offset + self.buffer_size])
+    }
+
+    /// the output buffers of every channel, in order, to render into. There is one for every
+    /// channel even if the block is empty.
+    pub fn channels_mut(&mut self) -> impl Iterator<Item = &'_ mut [f32]> {
+        let buffer_size = self.buffer_size;
+        let mut rest = &mut self.output_buffer[..buffer_size * self.num_channels];
+        (0..self.num_channels).map(move |_| {
+            let (channel, tail) = std::mem::take(&mut rest).split_at_mut(buffer_size);
+            rest = tail;
+            channel
+        })
+    }
+
+    /// the output samples of every channel at each frame, in order
+    pub fn frames(&self) -> impl Iterator<Item = impl Iterator<Item = f32> + '_> + '_ {
+        (0..self.buffer_size).map(move |frame| {
+            (0..self.num_channels)
+                .map(move |channel| self.output_buffer[channel * self.buffer_size + frame])
+        })
+    }
+
+    /// the highest absolute level of an output channel in this block
+    pub fn peak(&self, channel: usize) -> f32 {
+        self.channels().nth(channel).map_or(0.0, utils::peak)
+    }
+
+    /// the RMS level of an output channel in this block
+    pub fn rms(&self, channel: usize) -> f32 {
+        self.channels().nth(channel).map_or(0.0, utils::rms)
+    }
+
+    /// the highest peak of any output channel in this block
+    pub fn magnitude(&self) -> f32 {
+        self.channels()
+            .fold(0.0, |peak, output| peak.max(utils::peak(output)))
+    }
+
+    /// the highest peak of any output channel in this block, in dB
+    pub fn magnitude_db(&self) -> f32 {
+        20.0 * self.magnitude().log10()
+    }
+
+    /// Copy the output of another block of the same length into this one. A mono block is
+    /// copied to every channel, others are copied channel for channel, leaving any channels the
+    /// other block doesn't have as they are.
+    pub fn copy_from(&mut self, other: &PlaybackContext) {
+        self.each_channel_from(other, |output, input| output.copy_from_slice(input));
+    }
+
+    /// mix the output of another block of the same length into this one with a gain, routing
+    /// its channels as `copy_from` does
+    pub fn add_from(&mut self, other: &PlaybackContext, gain: f32) {
+        self.each_channel_from(other, |output, input| {
+            for (sample, input) in output.iter_mut().zip(input) {
+                *sample += gain * input;
+            }
+        });
+    }
+
+    /// multiply every output channel by a gain
+    pub fn apply_gain(&mut self, gain: f32) {
+        self.apply_gain_ramp(gain, gain);
+    }
+
+    /// multiply every output channel by a gain moving linearly from `start` to `end` over
+    /// the block
+    pub fn apply_gain_ramp(&mut self, start: f32, end: f32) {
+        for output in self.channels_mut() {
+            apply_gain_ramp(output, start, end);
+        }
+    }
+
+    fn each_channel_from(
+        &mut self,
+        other: &PlaybackContext,
+        mut f: impl FnMut(&mut [f32], &[f32]),
+    ) {
+        debug_assert_eq!(self.buffer_size, other.buffer_size);
+        if self.buffer_size != other.buffer_size {
+            return;
+        }
+        if other.num_channels == 1 {
+            let input = &other.output_buffer[..other.buffer_size];
+            for output in self.channels_mut() {
+                f(output, input);
+            }
+        } else {
+            for (output, input) in self.channels_mut().zip(other.channels()) {
+                f(output, input);
+            }
+        }
+    }
+
+    /// the output buffers of every channel, in order
+    pub fn channels(&self) -> impl Iterator<Item = &'_ [f32]> {
+        (0..self.num_channels).map(move |idx| {
+            let offset = idx * self.buffer_size;
+            &self.output_buffer[offset..offset + self.buffer_size]
+        })
+    }
 }
 
-/// start the audio stream
-pub fn audio_stream(mut main_callback: impl FnMut(PlaybackContext) + Send + 'static) -> Stream {
-    let host = cpal::default_host();
-    let output_device = host.default_output_device().expect("no output found");
-    let config = output_device
-        .default_output_config()
-        .expect("no default output config")
-        .config();
+/// start the audio stream on the default output
+pub fn audio_stream(main_callback: impl FnMut(PlaybackContext) + Send + 'static) -> Stream {
+    let device = output_device(&host(), None).expect("failed to open stream");
+    output_stream(main_callback, None, &device, false, |err| {
+        eprintln!("{}", err)
+    })
+    .expect("failed to open stream")
+}
+
+/// start the audio stream on the default output along with an input stream on the default
+/// input, delivering the input captured for each block in its context. Both streams must be
+/// kept alive.
+pub fn duplex_stream(
+    main_callback: impl FnMut(PlaybackContext) + Send + 'static,
+) -> Result<(Stream, Stream), String> {
+    let (input_stream, input) = input_stream(None)?;
+    let device = output_device(&host(), None)?;
+    let output_stream = output_stream(main_callback, Some(input), &device, false, |err| {
+        eprintln!("{}", err)
+    })?;
+    Ok((output_stream, input_stream))
+}
+
+/// the host streams are opened on, which is ASIO on Windows when it is enabled and has a
+/// driver installed, because WASAPI in shared mode adds too much latency for scrubbing
+pub fn host() -> cpal::Host {
+    #[cfg(all(windows, feature = "asio"))]
+    {
+        if let Ok(host) = cpal::host_from_id(cpal::HostId::Asio) {
+            return host;
+        }
+    }
+    cpal::default_host()
+}
 
+/// the output with a name, or the default output
+pub fn output_device(host: &cpal::Host, name: Option<&str>) -> Result<cpal::Device, String> {
+    if let Some(name) = name {
+        let mut devices = host.output_devices().map_err(|e| e.to_string())?;
+        return devices
+            .find(|device| device.name().map_or(false, |device| device == name))
+            .ok_or_else(|| format!("no output named {}", name));
+    }
+    host.default_output_device()
+        .ok_or_else(|| "no output found".to_string())
+}
+
+/// What `$PLAY_SAMPLE_RATE`, `$PLAY_CHANNELS` and `$PLAY_BUFFER_SIZE` ask of the output,
+/// with anything unset left to the device's default
+#[derive(Clone, Copy, Default, Debug)]
+struct StreamRequest {
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    buffer_size: Option<u32>,
+}
+
+impl StreamRequest {
+    fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+        Self {
+            sample_rate: var("PLAY_SAMPLE_RATE"),
+            channels: var("PLAY_CHANNELS"),
+            buffer_size: var("PLAY_BUFFER_SIZE"),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sample_rate.is_none() && self.channels.is_none() && self.buffer_size.is_none()
+    }
+}
+
+/// The config to open the output with, which is the device's default unless a config was
+/// asked for. Then the supported configs that run at the requested rate are scored by how
+/// close their channels are, preferring enough channels and then `f32` samples, and the first
+/// channels of the closest one are used, since an interface may have many more outputs than
+/// are wired up. The buffer size is clamped to what the config supports.
+fn output_config(device: &cpal::Device) -> Result<(cpal::StreamConfig, SampleFormat), String> {
+    let request = StreamRequest::from_env();
+    let default = device.default_output_config().map_err(|e| e.to_string())?;
+    if request.is_empty() {
+        return Ok((default.config(), default.sample_format()));
+    }
+    let sample_rate = request.sample_rate.unwrap_or(default.sample_rate().0);
+    let channels = request
+        .channels
+        .unwrap_or_else(|| default.channels())
+        .max(1);
+    let ranges = device
+        .supported_output_configs()
+        .map_err(|e| e.to_string())?
+        .collect::<Vec<_>>();
+    let score = |range: &cpal::SupportedStreamConfigRange| {
+        let format = match range.sample_format() {
+            SampleFormat::F32 => 0,
+            SampleFormat::I16 => 1,
+            SampleFormat::U16 => 2,
+        };
+        let distance = (range.channels() as i32 - channels as i32).abs();
+        (range.channels() < channels, distance, format)
+    };
+    let range = ranges
+        .iter()
+        .filter(|range| {
+            (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&sample_rate)
+        })
+        .min_by_key(|range| score(range))
+        .ok_or_else(|| {
+            let available = ranges
+                .iter()
+                .map(|range| {
+                    format!(
+                        "{} channels of {:?} at {}-{}Hz",
+                        range.channels(),
+                        range.sample_format(),
+                        range.min_sample_rate().0,
+                        range.max_sample_rate().0
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!(
+                "no output config runs at {}Hz, the device supports {}",
+                sample_rate,
+                available.join(", ")
+            )
+        })?;
+    let supported = range
+        .clone()
+        .with_sample_rate(cpal::SampleRate(sample_rate));
+    let mut config = supported.config();
+    config.channels = channels.min(config.channels);
+    if let Some(frames) = request.buffer_size {
+        let frames = match supported.buffer_size() {
+            cpal::SupportedBufferSize::Range { min, max } => frames.clamp(*min, *max),
+            cpal::SupportedBufferSize::Unknown => frames,
+        };
+        config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+    Ok((config, supported.sample_format()))
+}
+
+/// the names of the outputs of the host streams are opened on, for choosing `$PLAY_DEVICE`
+pub fn output_devices() -> Vec<String> {
+    host()
+        .output_devices()
+        .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// open an output stream on a device with the config asked for by the environment, in
+/// whatever sample format the device takes
+pub fn output_stream(
+    main_callback: impl FnMut(PlaybackContext) + Send + 'static,
+    input: Option<InputMonitor>,
+    device: &cpal::Device,
+    device_changed: bool,
+    error_callback: impl FnMut(StreamError) + Send + 'static,
+) -> Result<Stream, String> {
+    let (config, sample_format) = output_config(device)?;
+    // integer outputs are dithered with a triangular noise of one step when `$PLAY_DITHER`
+    // is set, which decorrelates the rounding error from quiet material
+    let dither = if std::env::var_os("PLAY_DITHER").is_some() {
+        1.0 / 32768.0
+    } else {
+        0.0
+    };
+    match sample_format {
+        SampleFormat::F32 => build_output_stream::<f32>(
+            device,
+            &config,
+            main_callback,
+            input,
+            device_changed,
+            0.0,
+            error_callback,
+        ),
+        SampleFormat::I16 => build_output_stream::<i16>(
+            device,
+            &config,
+            main_callback,
+            input,
+            device_changed,
+            dither,
+            error_callback,
+        ),
+        SampleFormat::U16 => build_output_stream::<u16>(
+            device,
+            &config,
+            main_callback,
+            input,
+            device_changed,
+            dither,
+            error_callback,
+        ),
+    }
+}
+
+/// open an output stream writing samples of type `T`, converted from the `f32` the callback
+/// renders, with `dither` the size of the triangular dither added before converting
+fn build_output_stream<T: Sample>(
+    output_device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut main_callback: impl FnMut(PlaybackContext) + Send + 'static,
+    mut input: Option<InputMonitor>,
+    mut device_changed: bool,
+    dither: f32,
+    error_callback: impl FnMut(StreamError) + Send + 'static,
+) -> Result<Stream, String> {
     let sample_rate = config.sample_rate.0 as f64;
     let num_channels = config.channels as usize;
+    let num_inputs = input.as_ref().map_or(0, |input| input.num_channels);
     let mut output_buffer = vec![];
     let mut input_buffer = vec![];
 
     output_buffer.resize_with(1 << 16, || 0.0);
     input_buffer.resize_with(1 << 16, || 0.0);
 
-    let callback = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+    // when the last block will have finished playing
+    let mut expected: Option<cpal::StreamInstant> = None;
+    // when the first block played, which stream time is counted from
+    let mut started: Option<cpal::StreamInstant> = None;
+    // xorshift state for the dither
+    let mut noise = 0x9e37_79b9u32;
+    let callback = move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+        span!("output callback");
         let buffer_size = data.len() / num_channels;
-        output_buffer.resize(data.len(), 0.0);
+        let block = Duration::from_secs_f64((buffer_size as f64) / sample_rate);
+        let timestamp = info.timestamp();
+        let playback = timestamp.playback;
+        let output_latency = playback
+            .duration_since(&timestamp.callback)
+            .map_or(0, |latency| {
+                (latency.as_secs_f64() * sample_rate).round() as usize
+            });
+        // blocks play back to back, if this one starts more than half a block late the
+        // device ran dry in between
+        let xrun = expected
+            .and_then(|expected| playback.duration_since(&expected))
+            .map_or(false, |late| late > block / 2);
+        expected = playback.add(block);
+        let first = *started.get_or_insert(playback);
+        let stream_time = playback.duration_since(&first).map_or(0, |elapsed| {
+            (elapsed.as_secs_f64() * sample_rate).round() as u64
+        });
         for sample in data.iter_mut() {
-            *sample = 0.0;
+            *sample = T::from(&0.0f32);
         }
-        for sample in &mut output_buffer.iter_mut() {
-            *sample = 0.0;
+        // blocks longer than the buffer are rendered in pieces, rather than growing the
+        // buffer on the audio thread
+        let max_frames = output_buffer.len() / num_channels.max(num_inputs);
+        for (index, chunk) in data.chunks_mut(max_frames * num_channels).enumerate() {
+            let frames = chunk.len() / num_channels;
+            let output = &mut output_buffer[..chunk.len()];
+            let input_buffer = &mut input_buffer[..frames * num_inputs];
+            if let Some(input) = &mut input {
+                input.read_into(input_buffer, frames);
+            }
+            // the callback mixes into the output, so every block starts from silence and any
+            // frames it doesn't write, such as the tail after the end of a file, stay silent
+            for sample in output.iter_mut() {
+                *sample = 0.0;
+            }
+
+            let context = PlaybackContext {
+                buffer_size: frames,
+                num_channels,
+                sample_rate,
+                xrun: xrun && index == 0,
+                stream_time: stream_time + (index * max_frames) as u64,
+                device_changed,
+                num_inputs,
+                timestamp: Some(timestamp),
+                output_latency,
+                output_buffer: &mut *output,
+                input_buffer,
+            };
+            device_changed = false;
+
+            main_callback(context);
+            if dither > 0.0 {
+                interleave_into(output, chunk, num_channels, |sample| {
+                    let mut uniform = || {
+                        noise ^= noise << 13;
+                        noise ^= noise >> 17;
+                        noise ^= noise << 5;
+                        (noise as f32) / (u32::MAX as f32)
+                    };
+                    let offset = (uniform() - uniform()) * dither;
+                    T::from(&(sample + offset))
+                });
+            } else {
+                interleave_into(output, chunk, num_channels, |sample| T::from(&sample));
+            }
+        }
+    };
+
+    output_device
+        .build_output_stream(config, callback, error_callback)
+        .map_err(|e| e.to_string())
+}
+
+/// interleave a block of deinterleaved samples into the device buffer, converting each one
+fn interleave_into<T>(
+    input: &[f32],
+    output: &mut [T],
+    num_channels: usize,
+    mut convert: impl FnMut(f32) -> T,
+) {
+    debug_assert_eq!(input.len(), output.len());
+    let num_samples = input.len() / num_channels;
+    for sm in 0..num_samples {
+        for ch in 0..num_channels {
+            output[sm * num_channels + ch] = convert(input[ch * num_samples + sm]);
+        }
+    }
+}
+
+/// How the audio callback has kept up since the stream started, updated by the audio
+/// thread and read by the UI
+#[derive(Default, Debug)]
+pub struct StreamStats {
+    blocks: AtomicU64,
+    xruns: AtomicU64,
+    overloads: AtomicU64,
+    /// in nanoseconds
+    worst_callback: AtomicU64,
+    /// the longest callback as a fraction of its block, in thousandths
+    worst_load: AtomicU64,
+}
+
+impl StreamStats {
+    /// the number of blocks rendered
+    pub fn blocks(&self) -> u64 {
+        self.blocks.load(Ordering::SeqCst)
+    }
+
+    /// the number of times the device ran dry between blocks
+    pub fn xruns(&self) -> u64 {
+        self.xruns.load(Ordering::SeqCst)
+    }
+
+    /// the number of blocks that took longer to render than to play
+    pub fn overloads(&self) -> u64 {
+        self.overloads.load(Ordering::SeqCst)
+    }
+
+    /// the longest time a block took to render
+    pub fn worst_callback(&self) -> Duration {
+        Duration::from_nanos(self.worst_callback.load(Ordering::SeqCst))
+    }
+
+    /// the longest time a block took to render, as a fraction of the time it plays for
+    pub fn worst_load(&self) -> f64 {
+        (self.worst_load.load(Ordering::SeqCst) as f64) / 1000.0
+    }
+
+    /// start counting again from now
+    pub fn reset(&self) {
+        for stat in &[
+            &self.blocks,
+            &self.xruns,
+            &self.overloads,
+            &self.worst_callback,
+            &self.worst_load,
+        ] {
+            stat.store(0, Ordering::SeqCst);
+        }
+    }
+
+    fn record(&self, xrun: bool, elapsed: Duration, block: Duration) {
+        self.blocks.fetch_add(1, Ordering::SeqCst);
+        if xrun {
+            self.xruns.fetch_add(1, Ordering::SeqCst);
+        }
+        if elapsed > block {
+            self.overloads.fetch_add(1, Ordering::SeqCst);
+        }
+        let load = elapsed.as_secs_f64() / block.as_secs_f64().max(f64::EPSILON);
+        self.worst_callback
+            .fetch_max(elapsed.as_nanos() as u64, Ordering::SeqCst);
+        self.worst_load
+            .fetch_max((load * 1000.0) as u64, Ordering::SeqCst);
+    }
+}
+
+/// Controls for the last stage of the output, shared between the UI and the audio thread
+#[derive(Debug)]
+pub struct OutputControls {
+    /// the output volume, as the bits of an `f32` in dB
+    volume: AtomicU32,
+    muted: AtomicBool,
+    limiter: AtomicBool,
+    /// the gain reduction of the limiter in the last block, as the bits of an `f32` in dB
+    reduction: AtomicU32,
+}
+
+impl Default for OutputControls {
+    fn default() -> Self {
+        Self {
+            volume: AtomicU32::new(0.0f32.to_bits()),
+            muted: AtomicBool::new(false),
+            limiter: AtomicBool::new(true),
+            reduction: AtomicU32::new(0.0f32.to_bits()),
+        }
+    }
+}
+
+impl OutputControls {
+    /// the volume of the output in dB, applied after every other gain
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Ordering::SeqCst))
+    }
+
+    pub fn set_volume(&self, db: f32) {
+        self.volume.store(db.to_bits(), Ordering::SeqCst);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::SeqCst)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::SeqCst);
+    }
+
+    /// true if the output is limited so that it can't clip, which is the default
+    pub fn limiter(&self) -> bool {
+        self.limiter.load(Ordering::SeqCst)
+    }
+
+    pub fn set_limiter(&self, limiter: bool) {
+        self.limiter.store(limiter, Ordering::SeqCst);
+    }
+
+    /// how far the limiter turned the last block down, in dB
+    pub fn reduction(&self) -> f32 {
+        f32::from_bits(self.reduction.load(Ordering::SeqCst))
+    }
+}
+
+/// Wrap a callback with the last stage of the output. The volume ramps over each block to
+/// where it was set, so moving it or muting doesn't click. While the limiter is on, the gain is
+/// pulled down over a block to bring its peak under the ceiling and recovers over the
+/// following blocks, and whatever still rises above the knee is soft clipped, so hot files
+/// or many voices summed together can't hard clip the interface.
+fn output_stage(
+    controls: Arc<OutputControls>,
+    mut main_callback: impl FnMut(PlaybackContext) + Send + 'static,
+) -> impl FnMut(PlaybackContext) + Send + 'static {
+    let mut volume = 1.0f32;
+    let mut gain = 1.0f32;
+    move |mut context: PlaybackContext| {
+        main_callback(context.reborrow());
+        let target = if controls.muted() {
+            0.0
+        } else {
+            db_to_gain(controls.volume())
+        };
+        if volume != 1.0 || target != 1.0 {
+            context.apply_gain_ramp(volume, target);
+        }
+        volume = target;
+        if !controls.limiter() {
+            gain = 1.0;
+            controls.reduction.store(0.0f32.to_bits(), Ordering::SeqCst);
+            return;
+        }
+        let peak = context.magnitude();
+        let seconds = (context.buffer_size as f64 / context.sample_rate) as f32;
+        let released = (gain * 10f32.powf(LIMIT_RELEASE * seconds / 20.0)).min(1.0);
+        let target = if peak * released > LIMIT_CEILING {
+            LIMIT_CEILING / peak
+        } else {
+            released
+        };
+        context.apply_gain_ramp(gain, target);
+        for output in context.channels_mut() {
+            for sample in output.iter_mut() {
+                *sample = soft_clip(*sample);
+            }
+        }
+        gain = target;
+        controls
+            .reduction
+            .store((-20.0 * gain.log10()).to_bits(), Ordering::SeqCst);
+    }
+}
+
+/// pass samples under the knee through and round off those above it, so that nothing leaves
+/// above the ceiling
+fn soft_clip(sample: f32) -> f32 {
+    let level = sample.abs();
+    if level <= LIMIT_KNEE {
+        return sample;
+    }
+    let headroom = LIMIT_CEILING - LIMIT_KNEE;
+    let clipped = LIMIT_KNEE + headroom * ((level - LIMIT_KNEE) / headroom).tanh();
+    clipped.copysign(sample)
+}
+
+/// wrap a callback to time every block it renders into `stats`
+fn measured(
+    stats: Arc<StreamStats>,
+    mut main_callback: impl FnMut(PlaybackContext) + Send + 'static,
+) -> impl FnMut(PlaybackContext) + Send + 'static {
+    move |context: PlaybackContext| {
+        let xrun = context.xrun;
+        let block = Duration::from_secs_f64((context.buffer_size as f64) / context.sample_rate);
+        let start = Instant::now();
+        main_callback(context);
+        stats.record(xrun, start.elapsed(), block);
+    }
+}
+
+/// Wrap a callback to render in blocks of `block_size` frames whatever size the device asks
+/// for, or in the device's blocks if it is `None`. Frames of a block the device didn't take
+/// are kept for its next callback, so rendering stays in step with the device without adding
+/// latency. The input of a duplex stream isn't passed on to fixed blocks.
+pub fn fixed_blocks(
+    block_size: Option<usize>,
+    mut main_callback: impl FnMut(PlaybackContext) + Send + 'static,
+) -> impl FnMut(PlaybackContext) + Send + 'static {
+    let mut buffer = vec![0.0; 1 << 16];
+    // the channels and frames in the last block, and how many of its frames have played
+    let mut num_channels = 0;
+    let mut rendered = 0;
+    let mut position = 0;
+    // flags held until the next block is rendered
+    let mut xrun = false;
+    let mut device_changed = false;
+    move |mut context: PlaybackContext| {
+        let block_size = match block_size {
+            Some(block_size) => block_size.clamp(1, buffer.len() / context.num_channels.max(1)),
+            None => return main_callback(context),
+        };
+        if context.num_channels != num_channels {
+            num_channels = context.num_channels;
+            rendered = 0;
+            position = 0;
+        }
+        xrun |= context.xrun;
+        device_changed |= context.device_changed;
+        let mut written = 0;
+        while written < context.buffer_size {
+            if position == rendered {
+                let output = &mut buffer[..block_size * num_channels];
+                for sample in output.iter_mut() {
+                    *sample = 0.0;
+                }
+                main_callback(PlaybackContext {
+                    buffer_size: block_size,
+                    sample_rate: context.sample_rate,
+                    num_channels,
+                    xrun,
+                    stream_time: context.stream_time + written as u64,
+                    device_changed,
+                    num_inputs: 0,
+                    timestamp: context.timestamp,
+                    output_latency: context.output_latency + written,
+                    output_buffer: output,
+                    input_buffer: &[],
+                });
+                xrun = false;
+                device_changed = false;
+                rendered = block_size;
+                position = 0;
+            }
+            let frames = (rendered - position).min(context.buffer_size - written);
+            for ch in 0..num_channels {
+                let block = &buffer[ch * rendered + position..][..frames];
+                context.get_output(ch)[written..written + frames].copy_from_slice(block);
+            }
+            position += frames;
+            written += frames;
+        }
+    }
+}
+
+/// A stream without a device, which renders blocks only when asked to and as fast as the
+/// callback can, so that the player can be rendered faster than real time or driven by tests
+pub struct OfflineStream<F> {
+    main_callback: F,
+    sample_rate: f64,
+    block_size: usize,
+    num_channels: usize,
+    buffer: Vec<f32>,
+    stream_time: u64,
+}
+
+/// start a stream of `num_channels` channels at `sample_rate` that renders in blocks of
+/// `block_size` frames
+pub fn offline_stream<F: FnMut(PlaybackContext)>(
+    sample_rate: f64,
+    block_size: usize,
+    num_channels: usize,
+    main_callback: F,
+) -> OfflineStream<F> {
+    let block_size = block_size.max(1);
+    OfflineStream {
+        main_callback,
+        sample_rate,
+        block_size,
+        num_channels,
+        buffer: vec![0.0; block_size * num_channels],
+        stream_time: 0,
+    }
+}
+
+impl<F: FnMut(PlaybackContext)> OfflineStream<F> {
+    /// how many frames have been rendered since the stream started
+    pub fn stream_time(&self) -> u64 {
+        self.stream_time
+    }
+
+    /// render the next `frames` frames, interleaved. The last block is cut short if `frames`
+    /// isn't a multiple of the block size.
+    pub fn render(&mut self, frames: usize) -> Vec<f32> {
+        let mut output = vec![0.0; frames * self.num_channels];
+        for chunk in output.chunks_mut(self.block_size * self.num_channels) {
+            let buffer_size = chunk.len() / self.num_channels;
+            let block = &mut self.buffer[..chunk.len()];
+            for sample in block.iter_mut() {
+                *sample = 0.0;
+            }
+            let mut context =
+                PlaybackContext::new(buffer_size, self.sample_rate, self.num_channels, block);
+            context.stream_time = self.stream_time;
+            (self.main_callback)(context);
+            interleave_into(block, chunk, self.num_channels, |sample| sample);
+            self.stream_time += buffer_size as u64;
+        }
+        output
+    }
+
+    /// render the next `frames` frames into a 32 bit float wav file
+    pub fn render_to_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        frames: usize,
+    ) -> Result<(), hound::Error> {
+        let spec = hound::WavSpec {
+            channels: self.num_channels as u16,
+            sample_rate: self.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        let mut remaining = frames;
+        while remaining > 0 {
+            let frames = remaining.min(self.block_size);
+            for sample in self.render(frames) {
+                writer.write_sample(sample)?;
+            }
+            remaining -= frames;
+        }
+        writer.finalize()
+    }
+}
+
+/// how long to wait before trying to open an output again after failing to
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Supervision {
+    /// the device of a stream went away, counting the streams that have been built
+    DeviceLost(u64),
+    /// close the stream, releasing its device until it is resumed
+    Suspend,
+    Resume,
+    /// reopen the stream on the output with this name, or the default output
+    Switch(Option<String>),
+    Stop,
+}
+
+/// The output of the main stream on its way to a cue output
+struct Cue {
+    rx: Mutex<Consumer<f32>>,
+    /// true while a cue output is open, otherwise the main stream doesn't feed it
+    cueing: AtomicBool,
+    /// the sample rate of the main stream, as the bits of an `f64`
+    sample_rate: AtomicU64,
+    /// the frames in the last block of the main stream
+    block_size: AtomicU64,
+}
+
+/// wrap a callback to feed the first two channels of what it renders to a cue output
+fn cue_tap(
+    mut main_callback: impl FnMut(PlaybackContext) + Send + 'static,
+) -> (impl FnMut(PlaybackContext) + Send + 'static, Arc<Cue>) {
+    let (mut tx, rx) = RingBuffer::new(1 << 16).split();
+    let cue = Arc::new(Cue {
+        rx: Mutex::new(rx),
+        cueing: AtomicBool::new(false),
+        sample_rate: AtomicU64::new(0),
+        block_size: AtomicU64::new(0),
+    });
+    let shared = Arc::clone(&cue);
+    let callback = move |mut context: PlaybackContext| {
+        main_callback(context.reborrow());
+        let cue = &shared;
+        cue.sample_rate
+            .store(context.sample_rate.to_bits(), Ordering::SeqCst);
+        cue.block_size
+            .store(context.buffer_size as u64, Ordering::SeqCst);
+        if !cue.cueing.load(Ordering::SeqCst) {
+            return;
         }
+        for mut frame in context.frames() {
+            let left = if let Some(left) = frame.next() {
+                left
+            } else {
+                return;
+            };
+            let right = frame.next().unwrap_or(left);
+            // if the cue output has stopped reading the output is dropped
+            tx.push_slice(&[left, right]);
+        }
+    };
+    (callback, cue)
+}
+
+/// A second output on another device, such as headphones, playing what the main stream
+/// plays. The device's clock drifts from the main one, so the cue is resampled by a fraction
+/// of a percent to hold its buffer steady. The cue output closes when this is dropped.
+pub struct CueStream {
+    _stream: Stream,
+    cue: Arc<Cue>,
+}
+
+impl Drop for CueStream {
+    fn drop(&mut self) {
+        self.cue.cueing.store(false, Ordering::SeqCst);
+    }
+}
+
+/// play the cue from a ring buffer onto a device, resampling it to follow the drift between
+/// the clocks
+fn build_cue_stream<T: Sample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    cue: Arc<Cue>,
+) -> Result<Stream, String> {
+    let num_channels = config.channels as usize;
+    // the frames read this block, after the last frame of the block before
+    let mut source = vec![0.0f32; 1 << 16];
+    let mut previous = [0.0f32; CUE_CHANNELS];
+    let mut fraction = 0.0f64;
+    let mut primed = false;
+    let callback = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+        let frames = data.len() / num_channels;
+        for sample in data.iter_mut() {
+            *sample = T::from(&0.0f32);
+        }
+        let mut rx = if let Ok(rx) = cue.rx.try_lock() {
+            rx
+        } else {
+            return;
+        };
+        let available = rx.len() / CUE_CHANNELS;
+        let block = cue.block_size.load(Ordering::SeqCst) as usize;
+        let target = block + 2 * frames;
+        if !primed && available < target {
+            return;
+        }
+        let drift = (available as f64 - target as f64) / (target as f64);
+        let ratio = 1.0 + (drift * CUE_DRIFT_GAIN).clamp(-CUE_MAX_DRIFT, CUE_MAX_DRIFT);
+        let wanted = (frames as f64) * ratio + fraction;
+        let whole = wanted.floor() as usize;
+        // running dry means waiting for the buffer to fill up again
+        primed = whole <= available && (whole + 1) * CUE_CHANNELS <= source.len();
+        if !primed {
+            fraction = 0.0;
+            return;
+        }
+        fraction = wanted - wanted.floor();
+        source[..CUE_CHANNELS].copy_from_slice(&previous);
+        rx.pop_slice(&mut source[CUE_CHANNELS..(whole + 1) * CUE_CHANNELS]);
+        drop(rx);
+        for (index, frame) in data.chunks_mut(num_channels).enumerate() {
+            let position = (index as f64) * (whole as f64) / (frames as f64);
+            let from = position.floor() as usize;
+            let t = (position - position.floor()) as f32;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let channel = channel.min(CUE_CHANNELS - 1);
+                let a = source[from * CUE_CHANNELS + channel];
+                let b = source[(from + 1).min(whole) * CUE_CHANNELS + channel];
+                *sample = T::from(&(a + (b - a) * t));
+            }
+        }
+        previous.copy_from_slice(&source[whole * CUE_CHANNELS..(whole + 1) * CUE_CHANNELS]);
+    };
+    device
+        .build_output_stream(config, callback, |err| eprintln!("{}", err))
+        .map_err(|e| e.to_string())
+}
+
+/// A handle to a running stream that can be shared with the UI
+#[derive(Clone)]
+pub struct StreamHandle {
+    stats: Arc<StreamStats>,
+    controls: Arc<OutputControls>,
+    cue: Arc<Cue>,
+    /// the name of the output the stream is open on, if it is open on a cpal device
+    device: Arc<Mutex<Option<String>>>,
+    /// the thread that owns the stream, if it can be suspended and switched
+    supervisor: Option<Sender<Supervision>>,
+}
+
+impl StreamHandle {
+    /// how the callback has kept up, across every device the stream has been opened on
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
+
+    /// the controls of the last stage of the output
+    pub fn controls(&self) -> &OutputControls {
+        &self.controls
+    }
+
+    /// a handle to no stream, for driving the UI without a device
+    #[cfg(test)]
+    pub(crate) fn detached() -> Self {
+        Self {
+            stats: Arc::new(StreamStats::default()),
+            controls: Arc::new(OutputControls::default()),
+            cue: cue_tap(|_| ()).1,
+            device: Arc::new(Mutex::new(None)),
+            supervisor: None,
+        }
+    }
+
+    /// the name of the output the stream was last opened on
+    pub fn device(&self) -> Option<String> {
+        self.device.lock().ok()?.clone()
+    }
+
+    fn send(&self, message: Supervision) -> Result<(), String> {
+        let supervisor = self
+            .supervisor
+            .as_ref()
+            .ok_or("this stream can't be suspended or switched")?;
+        supervisor
+            .send(message)
+            .map_err(|_| "the stream has stopped".to_string())
+    }
+
+    /// close the stream without touching the player, so that it stops using the CPU and an
+    /// exclusive device is free for other programs until the stream is resumed
+    pub fn suspend(&self) -> Result<(), String> {
+        self.send(Supervision::Suspend)
+    }
+
+    /// start playing what the stream plays on a second output, the one named `device` or
+    /// the one named by `$PLAY_CUE_DEVICE`, which must run at the same sample rate
+    pub fn open_cue(&self, device: Option<&str>) -> Result<CueStream, String> {
+        let name = device
+            .map(str::to_string)
+            .or_else(|| std::env::var("PLAY_CUE_DEVICE").ok())
+            .ok_or("no cue output chosen, set $PLAY_CUE_DEVICE")?;
+        let host = host();
+        let device = output_device(&host, Some(&name))?;
+        let supported = device.default_output_config().map_err(|e| e.to_string())?;
+        let sample_rate = f64::from_bits(self.cue.sample_rate.load(Ordering::SeqCst));
+        if (supported.sample_rate().0 as f64) != sample_rate {
+            return Err("the cue output runs at a different sample rate".to_string());
+        }
+        let config = supported.config();
+        let cue = Arc::clone(&self.cue);
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => build_cue_stream::<f32>(&device, &config, cue),
+            SampleFormat::I16 => build_cue_stream::<i16>(&device, &config, cue),
+            SampleFormat::U16 => build_cue_stream::<u16>(&device, &config, cue),
+        }?;
+        // whatever was left from the last time cueing stopped is stale
+        if let Ok(mut rx) = self.cue.rx.lock() {
+            let stale = rx.len();
+            rx.discard(stale);
+        }
+        stream.play().map_err(|e| e.to_string())?;
+        self.cue.cueing.store(true, Ordering::SeqCst);
+        Ok(CueStream {
+            _stream: stream,
+            cue: Arc::clone(&self.cue),
+        })
+    }
 
+    /// open the stream again after it was suspended
+    pub fn resume(&self) -> Result<(), String> {
+        self.send(Supervision::Resume)
+    }
+
+    /// reopen the stream on the output named `device`, or on the default output
+    pub fn switch_device(&self, device: Option<String>) -> Result<(), String> {
+        self.send(Supervision::Switch(device))
+    }
+}
+
+/// An output stream that is rebuilt on whatever device is then the default if its device
+/// goes away, such as when an interface is unplugged, even if its device was chosen by name.
+/// The callback lives on across rebuilds, so the player keeps its state, and is told about the
+/// change by `PlaybackContext::device_changed`. The stream time starts again from zero on the
+/// new device. The stream can also be suspended and switched to another device through its
+/// handle, and stops when this is dropped.
+pub struct SupervisedStream {
+    tx: Sender<Supervision>,
+    thread: Option<JoinHandle<()>>,
+    handle: StreamHandle,
+}
+
+impl SupervisedStream {
+    pub fn handle(&self) -> StreamHandle {
+        self.handle.clone()
+    }
+}
+
+/// wait while the stream is suspended, following any switch of device, returning whether
+/// the device was switched or `None` if the stream was stopped
+fn wait_for_resume(rx: &mpsc::Receiver<Supervision>, device: &mut Option<String>) -> Option<bool> {
+    let mut switched = false;
+    loop {
+        match rx.recv() {
+            Ok(Supervision::Resume) => return Some(switched),
+            Ok(Supervision::Switch(name)) => {
+                *device = name;
+                switched = true;
+            }
+            Ok(Supervision::Stop) | Err(_) => return None,
+            _ => (),
+        }
+    }
+}
+
+/// start the audio stream on the output with a name, or the default output, supervised so
+/// that it recovers from losing its device
+pub fn supervised_stream(
+    device: Option<String>,
+    main_callback: impl FnMut(PlaybackContext) + Send + 'static,
+) -> SupervisedStream {
+    let stats = Arc::new(StreamStats::default());
+    let controls = Arc::new(OutputControls::default());
+    let main_callback = output_stage(Arc::clone(&controls), main_callback);
+    let (main_callback, cue) = cue_tap(main_callback);
+    let main_callback = Arc::new(Mutex::new(measured(Arc::clone(&stats), main_callback)));
+    let (tx, rx) = mpsc::channel();
+    let errors = tx.clone();
+    let current = Arc::new(Mutex::new(None));
+    let opened_on = Arc::clone(&current);
+    // streams can't be sent between threads on every platform, so this thread builds and
+    // drops all of them
+    let thread = thread::spawn(move || {
+        let mut device = device;
+        let mut device_changed = false;
+        // true once the output was lost, until the stream has been opened on the default output
+        let mut fell_back = false;
+        let mut generation = 0;
+        loop {
+            generation += 1;
+            let shared = Arc::clone(&main_callback);
+            // the old stream is dead, so the lock is only ever contended while the new stream
+            // starts, and then the block is left silent rather than waiting
+            let callback = move |context: PlaybackContext| {
+                if let Ok(mut main_callback) = shared.try_lock() {
+                    (*main_callback)(context);
+                }
+            };
+            let errors = errors.clone();
+            let error_callback = move |err: StreamError| {
+                eprintln!("{}", err);
+                if let StreamError::DeviceNotAvailable = err {
+                    let _ = errors.send(Supervision::DeviceLost(generation));
+                }
+            };
+            let host = host();
+            let opened = output_device(&host, device.as_deref()).and_then(|output| {
+                let name = output.name().map_err(|e| e.to_string())?;
+                let stream =
+                    output_stream(callback, None, &output, device_changed, error_callback)?;
+                Ok((stream, name))
+            });
+            let stream = match opened {
+                Ok((stream, name)) => {
+                    if fell_back {
+                        eprintln!("lost the audio output, playing on {} instead", name);
+                        fell_back = false;
+                    }
+                    if let Ok(mut current) = opened_on.lock() {
+                        *current = Some(name);
+                    }
+                    stream
+                }
+                Err(e) => {
+                    eprintln!("failed to open stream: {}", e);
+                    // an output that can't be opened by name is tried again as the default
+                    // output, rather than retrying a device that may never come back
+                    if device.take().is_some() {
+                        fell_back = true;
+                        device_changed = true;
+                        continue;
+                    }
+                    match rx.recv_timeout(RETRY_INTERVAL) {
+                        Ok(Supervision::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                        Ok(Supervision::Suspend) => match wait_for_resume(&rx, &mut device) {
+                            Some(switched) => device_changed |= switched,
+                            None => return,
+                        },
+                        Ok(Supervision::Switch(name)) => {
+                            device = name;
+                            device_changed = true;
+                        }
+                        _ => (),
+                    }
+                    continue;
+                }
+            };
+            loop {
+                match rx.recv() {
+                    // the stream is rebuilt on the default output, whichever output was chosen
+                    Ok(Supervision::DeviceLost(lost)) if lost == generation => {
+                        fell_back = true;
+                        device = None;
+                        device_changed = true;
+                        break;
+                    }
+                    Ok(Supervision::Suspend) => {
+                        drop(stream);
+                        match wait_for_resume(&rx, &mut device) {
+                            Some(switched) => device_changed = switched,
+                            None => return,
+                        }
+                        break;
+                    }
+                    Ok(Supervision::Switch(name)) => {
+                        device = name;
+                        device_changed = true;
+                        break;
+                    }
+                    Ok(Supervision::Stop) | Err(_) => return,
+                    // losses reported more than once, or by streams already replaced
+                    _ => (),
+                }
+            }
+        }
+    });
+    SupervisedStream {
+        tx: tx.clone(),
+        thread: Some(thread),
+        handle: StreamHandle {
+            stats,
+            controls,
+            cue,
+            device: current,
+            supervisor: Some(tx),
+        },
+    }
+}
+
+impl Drop for SupervisedStream {
+    fn drop(&mut self) {
+        let _ = self.tx.send(Supervision::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// the number of output ports registered with JACK
+#[cfg(feature = "jack")]
+const JACK_CHANNELS: usize = 2;
+
+/// A client of the JACK server, playing through ports named `play:output_1`,
+/// `play:output_2`, and so on, which are left unconnected for the session to patch. The client
+/// closes when this is dropped.
+#[cfg(feature = "jack")]
+pub struct JackStream {
+    _client: Box<dyn std::any::Any>,
+    handle: StreamHandle,
+}
+
+#[cfg(feature = "jack")]
+impl JackStream {
+    /// a handle to the stream, which can't be suspended or switched since the JACK server
+    /// owns the device
+    pub fn handle(&self) -> StreamHandle {
+        self.handle.clone()
+    }
+}
+
+/// start the audio stream as a JACK client instead of on the default device
+#[cfg(feature = "jack")]
+pub fn jack_stream(
+    main_callback: impl FnMut(PlaybackContext) + Send + 'static,
+) -> Result<JackStream, String> {
+    let (client, _) = jack::Client::new("play", jack::ClientOptions::NO_START_SERVER)
+        .map_err(|e| e.to_string())?;
+    let mut ports = (0..JACK_CHANNELS)
+        .map(|ch| client.register_port(&format!("output_{}", ch + 1), jack::AudioOut::default()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let sample_rate = client.sample_rate() as f64;
+    let num_channels = JACK_CHANNELS;
+    let mut output_buffer = vec![0.0; 1 << 16];
+    // the frame time the first cycle started at, which stream time is counted from
+    let mut started = None;
+    // the frame time the next cycle should start at, if none were skipped
+    let mut expected = None;
+    let stats = Arc::new(StreamStats::default());
+    let controls = Arc::new(OutputControls::default());
+    let main_callback = output_stage(Arc::clone(&controls), main_callback);
+    let (main_callback, cue) = cue_tap(main_callback);
+    let mut main_callback = measured(Arc::clone(&stats), main_callback);
+
+    let process = move |_: &jack::Client, scope: &jack::ProcessScope| {
+        span!("jack process");
+        let frames = (scope.n_frames() as usize).min(output_buffer.len() / num_channels);
+        let frame_time = scope.last_frame_time();
+        let first = *started.get_or_insert(frame_time);
+        let xrun = expected.map_or(false, |expected| expected != frame_time);
+        expected = Some(frame_time.wrapping_add(scope.n_frames()));
+        let output = &mut output_buffer[..frames * num_channels];
+        for sample in output.iter_mut() {
+            *sample = 0.0;
+        }
         let context = PlaybackContext {
-            buffer_size,
-            num_channels,
+            buffer_size: frames,
             sample_rate,
-            output_buffer: &mut output_buffer,
+            num_channels,
+            xrun,
+            stream_time: frame_time.wrapping_sub(first) as u64,
+            device_changed: false,
+            num_inputs: 0,
+            timestamp: None,
+            output_latency: 0,
+            output_buffer: &mut *output,
+            input_buffer: &[],
         };
-
         main_callback(context);
-        interleave(&output_buffer, data, num_channels);
+        for (ch, port) in ports.iter_mut().enumerate() {
+            let samples = &output[ch * frames..(ch + 1) * frames];
+            port.as_mut_slice(scope)[..frames].copy_from_slice(samples);
+        }
+        jack::Control::Continue
     };
+    let client = client
+        .activate_async((), jack::ClosureProcessHandler::new(process))
+        .map_err(|e| e.to_string())?;
+    Ok(JackStream {
+        _client: Box::new(client),
+        handle: StreamHandle {
+            stats,
+            controls,
+            cue,
+            device: Arc::new(Mutex::new(None)),
+            supervisor: None,
+        },
+    })
+}
 
-    output_device
-        .build_output_stream(&config, callback, |err| eprintln!("{}", err))
-        .expect("failed to open stream")
+/// The audio captured by the input stream, waiting to be mixed into the output
+pub struct InputMonitor {
+    rx: Consumer<f32>,
+    num_channels: usize,
+    /// interleaved input read from the ring buffer, allocated up front
+    buffer: Vec<f32>,
+    /// the input of a block deinterleaved to be mixed into the output, allocated up front
+    block: Vec<f32>,
+}
+
+impl InputMonitor {
+    /// read up to a block of interleaved input into the buffer, returning the number of
+    /// frames read
+    fn read(&mut self, frames: usize) -> usize {
+        let num_inputs = self.num_channels;
+        let backlog = self.rx.len() / num_inputs;
+        if backlog > MAX_INPUT_BACKLOG * frames {
+            self.rx.discard((backlog - frames) * num_inputs);
+        }
+        let len = (frames * num_inputs).min(self.buffer.len());
+        self.rx.pop_slice(&mut self.buffer[..len]) / num_inputs
+    }
+
+    /// read a block of input into a deinterleaved buffer, filling any frames that haven't
+    /// arrived with silence
+    fn read_into(&mut self, output: &mut [f32], frames: usize) {
+        let read = self.read(frames);
+        for sample in output.iter_mut() {
+            *sample = 0.0;
+        }
+        for frame in 0..read {
+            for ch in 0..self.num_channels {
+                output[ch * frames + frame] = self.buffer[frame * self.num_channels + ch];
+            }
+        }
+    }
+
+    /// mix the input captured since the last block into the output, with a gain ramping from
+    /// `start` to `end`. Mono inputs play on every output, others play channel for channel.
+    pub fn mix(&mut self, context: &mut PlaybackContext, start: f32, end: f32) {
+        let frames = context.buffer_size;
+        let num_inputs = self.num_channels;
+        if frames * num_inputs > self.block.len() {
+            return;
+        }
+        let mut block = std::mem::take(&mut self.block);
+        self.read_into(&mut block[..frames * num_inputs], frames);
+        let mut input = PlaybackContext::new(
+            frames,
+            context.sample_rate,
+            num_inputs,
+            &mut block[..frames * num_inputs],
+        );
+        input.apply_gain_ramp(start, end);
+        context.add_from(&input, 1.0);
+        self.block = block;
+    }
+}
+
+/// build a stream pushing the samples of an input into a ring buffer, converted to `f32`
+fn build_capture_stream<T: Sample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut tx: Producer<f32>,
+) -> Result<Stream, String> {
+    let mut converted = vec![0.0f32; 1 << 16];
+    device
+        .build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                for chunk in data.chunks(converted.len()) {
+                    let converted = &mut converted[..chunk.len()];
+                    for (output, sample) in converted.iter_mut().zip(chunk) {
+                        *output = sample.to_f32();
+                    }
+                    // if the output has stopped reading the input is dropped
+                    tx.push_slice(converted);
+                }
+            },
+            |err| eprintln!("{}", err),
+        )
+        .map_err(|e| e.to_string())
+}
+
+/// start capturing from the default input, which must run at the same sample rate as the
+/// output with a name, or the default output
+pub fn input_stream(output: Option<&str>) -> Result<(Stream, InputMonitor), String> {
+    let host = host();
+    let input_device = host.default_input_device().ok_or("no input found")?;
+    let supported = input_device
+        .default_input_config()
+        .map_err(|e| e.to_string())?;
+    let sample_format = supported.sample_format();
+    let config = supported.config();
+    let output_rate = output_device(&host, output)
+        .ok()
+        .and_then(|device| output_config(&device).ok())
+        .map(|(config, _)| config.sample_rate);
+    if output_rate != Some(config.sample_rate) {
+        return Err("the input and output run at different sample rates".to_string());
+    }
+
+    let num_channels = config.channels as usize;
+    let (tx, rx) = RingBuffer::new(1 << 16).split();
+    let stream = match sample_format {
+        SampleFormat::F32 => build_capture_stream::<f32>(&input_device, &config, tx),
+        SampleFormat::I16 => build_capture_stream::<i16>(&input_device, &config, tx),
+        SampleFormat::U16 => build_capture_stream::<u16>(&input_device, &config, tx),
+    }?;
+    stream.play().map_err(|e| e.to_string())?;
+    let monitor = InputMonitor {
+        rx,
+        num_channels,
+        buffer: vec![0.0; 1 << 16],
+        block: vec![0.0; 1 << 16],
+    };
+    Ok((stream, monitor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_file::AudioFile;
+    use crate::sample_player::{sample_player, EndOfFile};
+    use basedrop::Collector;
+
+    #[test]
+    fn mono_block_mixes_into_every_channel() {
+        let mut mono = vec![1.0, 2.0];
+        let mono = PlaybackContext::new(2, 48000.0, 1, &mut mono);
+        let mut stereo = vec![0.5; 4];
+        let mut output = PlaybackContext::new(2, 48000.0, 2, &mut stereo);
+        output.add_from(&mono, 2.0);
+        assert_eq!(output.get_output(0), &[2.5, 4.5]);
+        assert_eq!(output.get_output(1), &[2.5, 4.5]);
+        output.apply_gain(0.5);
+        assert_eq!(output.get_output(1), &[1.25, 2.25]);
+    }
+
+    #[test]
+    fn copy_leaves_channels_the_source_lacks() {
+        let mut source = vec![1.0, 2.0, 3.0, 4.0];
+        let source = PlaybackContext::new(2, 48000.0, 2, &mut source);
+        let mut buffer = vec![9.0; 6];
+        let mut output = PlaybackContext::new(2, 48000.0, 3, &mut buffer);
+        output.copy_from(&source);
+        assert_eq!(output.get_output(0), &[1.0, 2.0]);
+        assert_eq!(output.get_output(1), &[3.0, 4.0]);
+        assert_eq!(output.get_output(2), &[9.0, 9.0]);
+    }
+
+    #[test]
+    fn offline_render_matches_file() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("play-offline-input-{}.wav", std::process::id()));
+        let output = dir.join(format!("play-offline-output-{}.wav", std::process::id()));
+        // a ramp on the left and its inverse on the right, longer than a block and not a
+        // multiple of one
+        let num_samples = 300;
+        let left = (0..num_samples).map(|n| (n as f32) / (num_samples as f32));
+        let right = (0..num_samples).map(|n| -(n as f32) / (num_samples as f32));
+        let file = AudioFile {
+            data: left.chain(right).collect(),
+            sample_rate: 44100.0,
+            num_channels: 2,
+            num_samples,
+        };
+        file.save(&input).unwrap();
+
+        let gc = Collector::new();
+        let (mut player, mut controller) = sample_player(&gc);
+        controller.open(input.to_str().unwrap()).unwrap();
+        controller.set_fade_time(0.0);
+        controller.set_end_of_file(EndOfFile::Stop);
+        controller.play();
+        let mut stream = offline_stream(44100.0, 128, 2, |mut context| {
+            player.advance(&mut context);
+        });
+        stream.render_to_file(&output, num_samples).unwrap();
+        assert_eq!(stream.stream_time(), num_samples as u64);
+
+        let mut reader = hound::WavReader::open(&output).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 44100);
+        let rendered = reader
+            .samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rendered.len(), 2 * num_samples);
+        // without a fade time the file plays as it is from the first frame
+        for (n, frame) in rendered.chunks(2).enumerate() {
+            let expected = [file.get_channel(0)[n], file.get_channel(1)[n]];
+            assert_eq!(frame, &expected[..], "frame {}", n);
+        }
+        let _ = std::fs::remove_file(input);
+        let _ = std::fs::remove_file(output);
+    }
 }
@@ -0,0 +1,41 @@
+use crate::utils::db_to_gain;
+
+/// A gain curve over the position in the file, given as breakpoints that the gain is
+/// interpolated linearly between. Before the first breakpoint and after the last the gain
+/// holds.
+pub struct Automation {
+    /// the position in seconds and linear gain of each breakpoint, in order
+    points: Vec<(f64, f32)>,
+}
+
+impl Automation {
+    /// create a curve from breakpoints of position in seconds and gain in decibels, in any
+    /// order
+    pub fn new(points: &[(f64, f32)]) -> Self {
+        let mut points = points
+            .iter()
+            .map(|(time, db)| (*time, db_to_gain(*db)))
+            .collect::<Vec<_>>();
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        Self { points }
+    }
+
+    /// the linear gain at a position, in seconds
+    pub fn gain(&self, time: f64) -> f32 {
+        let next = self.points.partition_point(|(t, _)| *t <= time);
+        match (self.points.get(next.wrapping_sub(1)), self.points.get(next)) {
+            (Some((t0, g0)), Some((t1, g1))) => {
+                let x = ((time - t0) / (t1 - t0)) as f32;
+                g0 + (g1 - g0) * x
+            }
+            (Some((_, gain)), None) | (None, Some((_, gain))) => *gain,
+            (None, None) => 1.0,
+        }
+    }
+
+    /// the position of the first breakpoint after a position, in seconds
+    pub fn next_point(&self, time: f64) -> Option<f64> {
+        let next = self.points.partition_point(|(t, _)| *t <= time);
+        self.points.get(next).map(|(t, _)| *t)
+    }
+}
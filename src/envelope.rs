@@ -0,0 +1,132 @@
+/// An attack, decay, sustain, release envelope applied to triggered voices. The default
+/// envelope holds full gain, and voices without a release fade out like the transport.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Envelope {
+    /// time to rise from silence to full gain, in seconds
+    pub attack: f64,
+    /// time to fall from full gain to the sustain level, in seconds
+    pub decay: f64,
+    /// the linear gain held until the voice is released
+    pub sustain: f32,
+    /// time to fall from the sustain level to silence once released, in seconds
+    pub release: f64,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    /// falling from the gain the voice was released at
+    Release(f32),
+    Done,
+}
+
+/// The position of one voice in its envelope, advanced once per block
+#[derive(Clone, Copy, Debug)]
+pub struct EnvelopeState {
+    envelope: Envelope,
+    stage: Stage,
+    gain: f32,
+}
+
+impl EnvelopeState {
+    pub fn new(envelope: Envelope) -> Self {
+        let (stage, gain) = if envelope.attack > 0.0 {
+            (Stage::Attack, 0.0)
+        } else {
+            (Stage::Decay, 1.0)
+        };
+        Self {
+            envelope,
+            stage,
+            gain,
+        }
+    }
+
+    /// the envelope gain at the start of the block
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// true if the envelope will fade the voice out when it is released
+    pub fn has_release(&self) -> bool {
+        self.envelope.release > 0.0
+    }
+
+    pub fn release(&mut self) {
+        if !matches!(self.stage, Stage::Release(_) | Stage::Done) {
+            self.stage = Stage::Release(self.gain);
+        }
+    }
+
+    /// true once the release has reached silence
+    pub fn is_done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    /// the state at the end of a block of `frames` samples. Stages change on block
+    /// boundaries, so a stage shorter than a block is stretched to fill it.
+    pub fn next(&self, frames: usize, sample_rate: f64) -> Self {
+        let step = |time: f64| {
+            if time > 0.0 {
+                ((frames as f64) / (time * sample_rate)) as f32
+            } else {
+                1.0
+            }
+        };
+        let sustain = self.envelope.sustain;
+        let (stage, gain) = match self.stage {
+            Stage::Attack => {
+                let gain = self.gain + step(self.envelope.attack);
+                if gain >= 1.0 {
+                    (Stage::Decay, 1.0)
+                } else {
+                    (Stage::Attack, gain)
+                }
+            }
+            Stage::Decay => {
+                let gain = self.gain - (1.0 - sustain) * step(self.envelope.decay);
+                if gain <= sustain {
+                    (Stage::Sustain, sustain)
+                } else {
+                    (Stage::Decay, gain)
+                }
+            }
+            Stage::Sustain => (Stage::Sustain, sustain),
+            Stage::Release(from) => {
+                let gain = self.gain - from * step(self.envelope.release);
+                if gain <= 0.0 {
+                    (Stage::Done, 0.0)
+                } else {
+                    (Stage::Release(from), gain)
+                }
+            }
+            Stage::Done => (Stage::Done, 0.0),
+        };
+        Self {
+            envelope: self.envelope,
+            stage,
+            gain,
+        }
+    }
+}
+
+/// the gain of a note played at a MIDI velocity, in decibels. With a sensitivity of 0 every
+/// note plays at full gain, with a sensitivity of 1 the gain follows the square of the
+/// velocity, so half velocity is about 12dB down.
+pub fn velocity_to_db(velocity: u8, sensitivity: f32) -> f32 {
+    let velocity = (velocity.clamp(1, 127) as f32) / 127.0;
+    sensitivity * 40.0 * velocity.log10()
+}
@@ -0,0 +1,396 @@
+use crate::utils::config_path;
+use druid::{KbKey, KeyEvent};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Something that can be done from the keyboard
+#[derive(druid::Data, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Action {
+    PlayPause,
+    SeekStart,
+    SkipForward,
+    NudgeBack,
+    NudgeForward,
+    NudgeBackFar,
+    NudgeForwardFar,
+    StepBack,
+    StepForward,
+    ZoomIn,
+    ZoomOut,
+    ZoomToFit,
+    Copy,
+    Trim,
+    FadeIn,
+    FadeOut,
+    GainUp,
+    GainDown,
+    Undo,
+    Redo,
+    Save,
+    SaveAs,
+    ToggleAB,
+}
+
+impl Action {
+    /// every action, in the order they are listed in the editor and config file
+    pub const ALL: &'static [Action] = &[
+        Action::PlayPause,
+        Action::SeekStart,
+        Action::SkipForward,
+        Action::NudgeBack,
+        Action::NudgeForward,
+        Action::NudgeBackFar,
+        Action::NudgeForwardFar,
+        Action::StepBack,
+        Action::StepForward,
+        Action::ZoomIn,
+        Action::ZoomOut,
+        Action::ZoomToFit,
+        Action::Copy,
+        Action::Trim,
+        Action::FadeIn,
+        Action::FadeOut,
+        Action::GainUp,
+        Action::GainDown,
+        Action::Undo,
+        Action::Redo,
+        Action::Save,
+        Action::SaveAs,
+        Action::ToggleAB,
+    ];
+
+    /// the name of the action in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::PlayPause => "play_pause",
+            Action::SeekStart => "seek_start",
+            Action::SkipForward => "skip_forward",
+            Action::NudgeBack => "nudge_back",
+            Action::NudgeForward => "nudge_forward",
+            Action::NudgeBackFar => "nudge_back_far",
+            Action::NudgeForwardFar => "nudge_forward_far",
+            Action::StepBack => "step_back",
+            Action::StepForward => "step_forward",
+            Action::ZoomIn => "zoom_in",
+            Action::ZoomOut => "zoom_out",
+            Action::ZoomToFit => "zoom_to_fit",
+            Action::Copy => "copy",
+            Action::Trim => "trim",
+            Action::FadeIn => "fade_in",
+            Action::FadeOut => "fade_out",
+            Action::GainUp => "gain_up",
+            Action::GainDown => "gain_down",
+            Action::Undo => "undo",
+            Action::Redo => "redo",
+            Action::Save => "save",
+            Action::SaveAs => "save_as",
+            Action::ToggleAB => "toggle_ab",
+        }
+    }
+
+    /// a description of the action for the keymap editor
+    pub fn description(self) -> &'static str {
+        match self {
+            Action::PlayPause => "Play / pause",
+            Action::SeekStart => "Seek to start",
+            Action::SkipForward => "Skip forward",
+            Action::NudgeBack => "Nudge back one frame",
+            Action::NudgeForward => "Nudge forward one frame",
+            Action::NudgeBackFar => "Nudge back 1000 frames",
+            Action::NudgeForwardFar => "Nudge forward 1000 frames",
+            Action::StepBack => "Step back one block and play it",
+            Action::StepForward => "Step forward one block and play it",
+            Action::ZoomIn => "Zoom in",
+            Action::ZoomOut => "Zoom out",
+            Action::ZoomToFit => "Zoom to fit",
+            Action::Copy => "Copy selection",
+            Action::Trim => "Trim to selection",
+            Action::FadeIn => "Fade in selection",
+            Action::FadeOut => "Fade out selection",
+            Action::GainUp => "Raise selection by 3 dB",
+            Action::GainDown => "Lower selection by 3 dB",
+            Action::Undo => "Undo",
+            Action::Redo => "Redo",
+            Action::Save => "Save",
+            Action::SaveAs => "Save as",
+            Action::ToggleAB => "Switch between A and B",
+        }
+    }
+
+    /// find an action by its config file name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|action| action.name() == name)
+    }
+}
+
+/// A key, along with the modifiers that must be held with it
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct KeyBinding {
+    key: String,
+    ctrl: bool,
+    alt: bool,
+    shift: bool,
+}
+
+impl KeyBinding {
+    /// a binding for a key with no modifiers, e.g. `"a"` or `"ArrowLeft"`
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: normalize_key(key),
+            ctrl: false,
+            alt: false,
+            shift: false,
+        }
+    }
+
+    pub fn ctrl(mut self) -> Self {
+        self.ctrl = true;
+        self
+    }
+
+    pub fn alt(mut self) -> Self {
+        self.alt = true;
+        self
+    }
+
+    pub fn shift(mut self) -> Self {
+        self.shift = true;
+        self
+    }
+
+    /// the binding matching a key press. Command on macOS counts as control.
+    pub fn from_event(event: &KeyEvent) -> Self {
+        let key = match &event.key {
+            KbKey::Character(c) => normalize_key(c),
+            key => key.to_string(),
+        };
+        Self {
+            key,
+            ctrl: event.mods.ctrl() || event.mods.meta(),
+            alt: event.mods.alt(),
+            shift: event.mods.shift(),
+        }
+    }
+
+    /// parse a binding like `Ctrl+Shift+z` from the config file
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('+').map(str::trim).collect::<Vec<_>>();
+        let key = parts.pop().filter(|key| !key.is_empty())?;
+        let mut binding = Self::new(key);
+        for modifier in parts {
+            match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" | "cmd" => binding.ctrl = true,
+                "alt" | "option" => binding.alt = true,
+                "shift" => binding.shift = true,
+                _ => return None,
+            }
+        }
+        Some(binding)
+    }
+
+    /// true if the key is itself a modifier, which can't be bound on its own
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self.key.as_str(),
+            "Shift" | "Control" | "Alt" | "Meta" | "Super" | "AltGraph"
+        )
+    }
+
+    pub fn is_escape(&self) -> bool {
+        self.key == "Escape"
+    }
+}
+
+/// give characters that can't appear in the config file a name, and ignore case
+fn normalize_key(key: &str) -> String {
+    match key {
+        " " => "Space".to_owned(),
+        "+" => "Plus".to_owned(),
+        "=" => "Equal".to_owned(),
+        "," => "Comma".to_owned(),
+        "#" => "Hash".to_owned(),
+        key if key.chars().count() == 1 => key.to_lowercase(),
+        key => key.to_owned(),
+    }
+}
+
+impl fmt::Display for KeyBinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+/// The mapping between key bindings and actions
+#[derive(Clone)]
+pub struct KeyMap {
+    bindings: HashMap<KeyBinding, Action>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let mut keymap = Self {
+            bindings: HashMap::new(),
+        };
+        let defaults = vec![
+            (Action::PlayPause, KeyBinding::new("Space")),
+            (Action::SeekStart, KeyBinding::new("Home")),
+            (Action::SkipForward, KeyBinding::new(".")),
+            (Action::NudgeBack, KeyBinding::new("ArrowLeft")),
+            (Action::NudgeForward, KeyBinding::new("ArrowRight")),
+            (Action::NudgeBackFar, KeyBinding::new("ArrowLeft").shift()),
+            (
+                Action::NudgeForwardFar,
+                KeyBinding::new("ArrowRight").shift(),
+            ),
+            (Action::StepBack, KeyBinding::new("ArrowLeft").alt()),
+            (Action::StepForward, KeyBinding::new("ArrowRight").alt()),
+            (Action::ZoomIn, KeyBinding::new("Equal")),
+            (Action::ZoomOut, KeyBinding::new("-")),
+            (Action::ZoomToFit, KeyBinding::new("0")),
+            (Action::Copy, KeyBinding::new("c").ctrl()),
+            (Action::Trim, KeyBinding::new("t").ctrl()),
+            (Action::FadeIn, KeyBinding::new("i")),
+            (Action::FadeOut, KeyBinding::new("o")),
+            (Action::GainUp, KeyBinding::new("ArrowUp").ctrl()),
+            (Action::GainDown, KeyBinding::new("ArrowDown").ctrl()),
+            (Action::Undo, KeyBinding::new("z").ctrl()),
+            (Action::Redo, KeyBinding::new("z").ctrl().shift()),
+            (Action::Redo, KeyBinding::new("y").ctrl()),
+            (Action::Save, KeyBinding::new("s").ctrl()),
+            (Action::SaveAs, KeyBinding::new("s").ctrl().shift()),
+            (Action::ToggleAB, KeyBinding::new("b")),
+        ];
+        for (action, binding) in defaults {
+            keymap.bind(action, binding);
+        }
+        keymap
+    }
+}
+
+impl KeyMap {
+    /// the action bound to a key, if any
+    pub fn action(&self, binding: &KeyBinding) -> Option<Action> {
+        self.bindings.get(binding).copied()
+    }
+
+    /// every key bound to an action
+    pub fn bindings_for(&self, action: Action) -> Vec<&KeyBinding> {
+        let mut bindings = self
+            .bindings
+            .iter()
+            .filter(|(_, a)| **a == action)
+            .map(|(binding, _)| binding)
+            .collect::<Vec<_>>();
+        bindings.sort_by_key(|binding| binding.to_string());
+        bindings
+    }
+
+    /// a list of the keys bound to an action, for display
+    pub fn describe(&self, action: Action) -> String {
+        self.bindings_for(action)
+            .iter()
+            .map(|binding| binding.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// bind a key to an action, replacing whatever it was bound to before
+    pub fn bind(&mut self, action: Action, binding: KeyBinding) {
+        self.bindings.insert(binding, action);
+    }
+
+    /// remove every binding for an action
+    pub fn unbind(&mut self, action: Action) {
+        self.bindings.retain(|_, a| *a != action);
+    }
+
+    /// replace the bindings of an action with a single key
+    pub fn rebind(&mut self, action: Action, binding: KeyBinding) {
+        self.unbind(action);
+        self.bind(action, binding);
+    }
+
+    /// load a keymap from a config file of `action = key, key` lines. Actions missing from the
+    /// file keep their default bindings.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut keymap = Self::default();
+        let config = fs::read_to_string(path)?;
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |msg: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", n + 1, msg),
+                )
+            };
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim();
+            let keys = parts
+                .next()
+                .ok_or_else(|| invalid("expected `action = key`".to_owned()))?;
+            let action = Action::from_name(name)
+                .ok_or_else(|| invalid(format!("unknown action `{}`", name)))?;
+            keymap.unbind(action);
+            for key in keys.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+                let binding = KeyBinding::parse(key)
+                    .ok_or_else(|| invalid(format!("invalid key `{}`", key)))?;
+                keymap.bind(action, binding);
+            }
+        }
+        Ok(keymap)
+    }
+
+    /// write the keymap to a config file
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let config = Action::ALL
+            .iter()
+            .map(|action| format!("{} = {}\n", action.name(), self.describe(*action)))
+            .collect::<String>();
+        fs::write(path, config)
+    }
+
+    /// where the user's keymap is stored
+    pub fn user_path() -> Option<PathBuf> {
+        config_path("keymap.conf")
+    }
+
+    /// load the user's keymap, falling back to the defaults if there isn't one
+    pub fn load_user() -> Self {
+        match Self::user_path() {
+            Some(path) if path.exists() => Self::load(&path).unwrap_or_else(|e| {
+                println!("failed to load keymap from {}: {}", path.display(), e);
+                Self::default()
+            }),
+            _ => Self::default(),
+        }
+    }
+
+    /// save the keymap as the user's keymap
+    pub fn save_user(&self) -> io::Result<()> {
+        let path = Self::user_path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory found"))?;
+        self.save(path)
+    }
+}
@@ -0,0 +1,204 @@
+use crate::audio_stream::{self, PlaybackContext};
+use crate::utils::config_path;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Sample, SampleFormat, Stream, StreamInstant};
+use ringbuf::{Producer, RingBuffer};
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// how long to play silence before the click, so that both streams have settled
+const CLICK_DELAY: f64 = 0.5;
+
+/// the length of the click, in seconds
+const CLICK_LENGTH: f64 = 0.001;
+
+/// how long to listen for the click after it is played
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// the input level at which the click is heard
+const THRESHOLD: f32 = 0.1;
+
+/// The latency of an output and the default input, in seconds
+#[derive(druid::Data, Clone, Copy, PartialEq, Debug)]
+pub struct Latency {
+    /// from the output callback writing a sample to the input capturing it
+    pub round_trip: f64,
+    /// from the input capturing a sample to the input callback reading it, as reported by
+    /// the device
+    pub input: f64,
+}
+
+/// when the output callback wrote the click, and where in its block
+struct Click {
+    callback: StreamInstant,
+    frame: usize,
+    /// the sample rate the output was opened at
+    sample_rate: f64,
+}
+
+/// when the input captured the block the click was heard in, and where in the block
+struct Capture {
+    callback: StreamInstant,
+    capture: StreamInstant,
+    frame: usize,
+}
+
+impl Latency {
+    /// from the output callback writing a sample to it being heard, the latency to
+    /// compensate the playhead cursor by
+    pub fn output(&self) -> f64 {
+        (self.round_trip - self.input).max(0.0)
+    }
+
+    /// the latency measured last time, if there is one
+    pub fn load_user() -> Option<Self> {
+        let config = fs::read_to_string(config_path("latency.conf")?).ok()?;
+        let value = |name: &str| {
+            config.lines().find_map(|line| {
+                let mut parts = line.splitn(2, '=');
+                if parts.next()?.trim() == name {
+                    parts.next()?.trim().parse::<f64>().ok()
+                } else {
+                    None
+                }
+            })
+        };
+        Some(Self {
+            round_trip: value("round_trip")?,
+            input: value("input")?,
+        })
+    }
+
+    /// store the latency to be loaded next time
+    pub fn save_user(&self) -> io::Result<()> {
+        let path = config_path("latency.conf")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory found"))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let config = format!("round_trip = {}\ninput = {}\n", self.round_trip, self.input);
+        fs::write(path, config)
+    }
+}
+
+/// Measure the latency of the output with a name, or the default output, by playing a click
+/// through it and listening for it on the default input, which should be connected to the
+/// output with a loopback cable or held near the speakers. The output is opened on the same
+/// host and with the same config as the main stream would be. Blocks until the click is heard
+/// or the measurement times out.
+pub fn measure(output: Option<&str>) -> Result<Latency, String> {
+    let host = audio_stream::host();
+    let output = audio_stream::output_device(&host, output)?;
+    let input = host.default_input_device().ok_or("no input found")?;
+    let supported = input.default_input_config().map_err(|e| e.to_string())?;
+    let sample_format = supported.sample_format();
+    let input_config = supported.config();
+    let input_rate = input_config.sample_rate.0 as f64;
+
+    let (mut click_tx, mut click_rx) = RingBuffer::<Click>::new(1).split();
+    let (capture_tx, mut capture_rx) = RingBuffer::<Capture>::new(1).split();
+    let clicked = Arc::new(AtomicBool::new(false));
+
+    let mut frames_written = 0;
+    let output_clicked = Arc::clone(&clicked);
+    let output_stream = audio_stream::output_stream(
+        move |mut context: PlaybackContext| {
+            let frames = context.buffer_size;
+            let click_at = (context.sample_rate * CLICK_DELAY) as usize;
+            let click_length = ((context.sample_rate * CLICK_LENGTH) as usize).max(1);
+            if !output_clicked.load(Ordering::SeqCst) && frames_written + frames > click_at {
+                let frame = click_at - frames_written;
+                let end = (frame + click_length).min(frames);
+                for output in context.channels_mut() {
+                    for sample in &mut output[frame..end] {
+                        *sample = 0.5;
+                    }
+                }
+                if let Some(timestamp) = context.timestamp {
+                    let _ = click_tx.push(Click {
+                        callback: timestamp.callback,
+                        frame,
+                        sample_rate: context.sample_rate,
+                    });
+                }
+                output_clicked.store(true, Ordering::SeqCst);
+            }
+            frames_written += frames;
+        },
+        None,
+        &output,
+        false,
+        |err| eprintln!("{}", err),
+    )?;
+
+    let input_stream = match sample_format {
+        SampleFormat::F32 => listen::<f32>(&input, &input_config, clicked, capture_tx),
+        SampleFormat::I16 => listen::<i16>(&input, &input_config, clicked, capture_tx),
+        SampleFormat::U16 => listen::<u16>(&input, &input_config, clicked, capture_tx),
+    }?;
+
+    input_stream.play().map_err(|e| e.to_string())?;
+    output_stream.play().map_err(|e| e.to_string())?;
+
+    let deadline = Instant::now() + Duration::from_secs_f64(CLICK_DELAY) + TIMEOUT;
+    let (mut click, mut capture) = (None, None);
+    while Instant::now() < deadline && (click.is_none() || capture.is_none()) {
+        click = click.or_else(|| click_rx.pop());
+        capture = capture.or_else(|| capture_rx.pop());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(output_stream);
+    drop(input_stream);
+
+    let click = click.ok_or("the click was never played")?;
+    let capture = capture.ok_or("the click was not heard, check the input is connected")?;
+    let heard_after = capture
+        .capture
+        .duration_since(&click.callback)
+        .ok_or("the click was heard before it was played, the input may be too noisy")?;
+    let round_trip = heard_after.as_secs_f64() + (capture.frame as f64) / input_rate
+        - (click.frame as f64) / click.sample_rate;
+    let input = capture
+        .callback
+        .duration_since(&capture.capture)
+        .map_or(0.0, |latency| latency.as_secs_f64());
+    Ok(Latency { round_trip, input })
+}
+
+/// build an input stream that listens for the click once it has been played, and sends
+/// where it was first heard
+fn listen<T: Sample>(
+    input: &cpal::Device,
+    config: &cpal::StreamConfig,
+    clicked: Arc<AtomicBool>,
+    mut capture_tx: Producer<Capture>,
+) -> Result<Stream, String> {
+    let input_channels = config.channels as usize;
+    let mut heard = false;
+    input
+        .build_input_stream(
+            config,
+            move |data: &[T], info: &cpal::InputCallbackInfo| {
+                if heard || !clicked.load(Ordering::SeqCst) {
+                    return;
+                }
+                if let Some(index) = data
+                    .iter()
+                    .position(|sample| sample.to_f32().abs() > THRESHOLD)
+                {
+                    heard = true;
+                    let timestamp = info.timestamp();
+                    let _ = capture_tx.push(Capture {
+                        callback: timestamp.callback,
+                        capture: timestamp.capture,
+                        frame: index / input_channels,
+                    });
+                }
+            },
+            |err| eprintln!("{}", err),
+        )
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,142 @@
+/// A speaker a channel is meant to be played on
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speaker {
+    FrontLeft,
+    FrontRight,
+    Center,
+    Lfe,
+    RearLeft,
+    RearRight,
+    SideLeft,
+    SideRight,
+}
+
+/// The speakers the channels of a file or device are meant for, in the order of the
+/// channels. Neither cpal nor wav files as read here report a layout, so it is assumed from
+/// the number of channels, using the usual wav order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    Quad,
+    Surround51,
+    Surround71,
+    /// channels that aren't meant for any speaker in particular
+    Discrete(usize),
+}
+
+use Speaker::*;
+
+impl Speaker {
+    /// the short name of the speaker, as it is usually printed on an interface
+    pub fn label(self) -> &'static str {
+        match self {
+            FrontLeft => "L",
+            FrontRight => "R",
+            Center => "C",
+            Lfe => "LFE",
+            RearLeft => "Lrs",
+            RearRight => "Rrs",
+            SideLeft => "Ls",
+            SideRight => "Rs",
+        }
+    }
+}
+
+impl ChannelLayout {
+    pub fn from_channels(num_channels: usize) -> Self {
+        match num_channels {
+            1 => ChannelLayout::Mono,
+            2 => ChannelLayout::Stereo,
+            4 => ChannelLayout::Quad,
+            6 => ChannelLayout::Surround51,
+            8 => ChannelLayout::Surround71,
+            n => ChannelLayout::Discrete(n),
+        }
+    }
+
+    /// the speaker of every channel, which is empty for discrete channels
+    pub fn speakers(self) -> &'static [Speaker] {
+        match self {
+            ChannelLayout::Mono => &[Center],
+            ChannelLayout::Stereo => &[FrontLeft, FrontRight],
+            ChannelLayout::Quad => &[FrontLeft, FrontRight, RearLeft, RearRight],
+            ChannelLayout::Surround51 => &[FrontLeft, FrontRight, Center, Lfe, SideLeft, SideRight],
+            ChannelLayout::Surround71 => &[
+                FrontLeft, FrontRight, Center, Lfe, RearLeft, RearRight, SideLeft, SideRight,
+            ],
+            ChannelLayout::Discrete(_) => &[],
+        }
+    }
+
+    pub fn num_channels(self) -> usize {
+        match self {
+            ChannelLayout::Discrete(n) => n,
+            layout => layout.speakers().len(),
+        }
+    }
+
+    /// the label of every channel, which is the speaker it is meant for or its number,
+    /// counting from 1, for discrete channels
+    pub fn labels(self) -> impl Iterator<Item = String> {
+        let speakers = self.speakers();
+        (0..self.num_channels()).map(move |channel| match speakers.get(channel) {
+            Some(speaker) => speaker.label().to_string(),
+            None => (channel + 1).to_string(),
+        })
+    }
+
+    fn has(self, speaker: Speaker) -> bool {
+        self.speakers().contains(&speaker)
+    }
+
+    /// The gain from a channel of a file with this layout to a channel of a device with
+    /// `output`. Channels play on their own speaker if the device has it, surrounds fold into
+    /// the other pair of surrounds or the fronts, the center is split between the fronts, and
+    /// the LFE is dropped if there is nowhere to play it. A mono file plays on the center, or
+    /// on both fronts if there isn't one, or on every channel of a discrete device. Discrete
+    /// channels play channel for channel.
+    pub fn gain(self, input: usize, output: ChannelLayout, to: usize) -> f32 {
+        const HALF_POWER: f32 = std::f32::consts::FRAC_1_SQRT_2;
+        let (from, to) = match (self.speakers().get(input), output.speakers().get(to)) {
+            (Some(&from), Some(&to)) => (from, to),
+            (Some(_), None) if self == ChannelLayout::Mono => return 1.0,
+            // with nothing known about either side, channels map straight across
+            _ => return if input == to { 1.0 } else { 0.0 },
+        };
+        if output.has(from) {
+            return if from == to { 1.0 } else { 0.0 };
+        }
+        let (left, right) = match from {
+            Center if self == ChannelLayout::Mono => (1.0, 1.0),
+            Center => (HALF_POWER, HALF_POWER),
+            FrontLeft => return if to == Center { HALF_POWER } else { 0.0 },
+            FrontRight => return if to == Center { HALF_POWER } else { 0.0 },
+            Lfe => return 0.0,
+            RearLeft | SideLeft | RearRight | SideRight => {
+                let other = match from {
+                    RearLeft => SideLeft,
+                    SideLeft => RearLeft,
+                    RearRight => SideRight,
+                    _ => RearRight,
+                };
+                if output.has(other) {
+                    return if to == other { 1.0 } else { 0.0 };
+                }
+                if output == ChannelLayout::Mono {
+                    return HALF_POWER;
+                }
+                if matches!(from, RearLeft | SideLeft) {
+                    (HALF_POWER, 0.0)
+                } else {
+                    (0.0, HALF_POWER)
+                }
+            }
+        };
+        match to {
+            FrontLeft => left,
+            FrontRight => right,
+            _ => 0.0,
+        }
+    }
+}
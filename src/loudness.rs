@@ -0,0 +1,125 @@
+use crate::audio_file::AudioFile;
+use std::f64::consts::PI;
+
+/// A second order IIR filter
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+/// The K-weighting filter, a high shelf approximating the acoustic effect of the head
+/// followed by a high pass
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    /// design the filter for a sample rate, the coefficients given by the standard are only
+    /// valid at 48kHz
+    fn new(sample_rate: f64) -> Self {
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10.0f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        };
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        };
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+}
+
+/// the weight of a channel in the loudness sum, assuming 5.1 files are ordered
+/// L, R, C, LFE, Ls, Rs
+fn channel_weight(channel: usize, num_channels: usize) -> f64 {
+    match (num_channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// convert a mean square power to loudness, in LUFS
+fn loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// The integrated loudness of a file in LUFS as measured by ITU-R BS.1770, or `None` if the
+/// file is shorter than one measurement block or silent
+pub fn integrated_loudness(file: &AudioFile) -> Option<f64> {
+    span!("integrated loudness");
+    // 400ms blocks, overlapping by 75%
+    let step = (file.sample_rate * 0.1) as usize;
+    if step == 0 || file.num_samples < 4 * step {
+        return None;
+    }
+    let num_steps = file.num_samples / step;
+    let mut steps = vec![0.0f64; num_steps];
+    for channel in 0..file.num_channels {
+        let weight = channel_weight(channel, file.num_channels);
+        if weight == 0.0 {
+            continue;
+        }
+        let mut filter = KWeighting::new(file.sample_rate);
+        let samples = &file.get_channel(channel)[0..num_steps * step];
+        for (n, sample) in samples.iter().enumerate() {
+            let y = filter.process(*sample as f64);
+            steps[n / step] += weight * y * y;
+        }
+    }
+    let window = (4 * step) as f64;
+    let blocks = steps
+        .windows(4)
+        .map(|block| block.iter().sum::<f64>() / window)
+        .collect::<Vec<_>>();
+
+    // the mean power of the blocks louder than a threshold, and the absolute gate
+    let gated_mean = |threshold: f64| {
+        let threshold = threshold.max(-70.0);
+        let (sum, count) = blocks
+            .iter()
+            .filter(|power| loudness(**power) > threshold)
+            .fold((0.0, 0), |(sum, count), power| (sum + power, count + 1));
+        if count == 0 {
+            None
+        } else {
+            Some(sum / (count as f64))
+        }
+    };
+    let absolute = gated_mean(-70.0)?;
+    let relative = gated_mean(loudness(absolute) - 10.0)?;
+    Some(loudness(relative))
+}
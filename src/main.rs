@@ -1,30 +1,109 @@
 #![allow(dead_code)]
+// declared first so that its macros are in scope in the other modules
+#[macro_use]
+mod trace;
 mod audio_file;
 mod audio_stream;
+mod automation;
+mod envelope;
+mod keymap;
+mod latency;
+mod layout;
+mod loudness;
+mod meter;
+mod midi;
 mod sample_player;
+#[cfg(feature = "soak")]
+mod soak;
 mod ui;
+mod undo;
 mod utils;
-use audio_stream::audio_stream;
+#[cfg(not(feature = "jack"))]
+use audio_stream::supervised_stream;
+use audio_stream::{fixed_blocks, offline_stream};
 use basedrop::Collector;
 use sample_player::*;
 
 fn main() -> Result<(), druid::PlatformError> {
     // get program input...
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        println!("usage is: `play <path>`");
+    if args.len() < 2 {
+        println!("usage is: `play <path> [<path or folder>...]`");
         std::process::exit(1);
     }
+    if args[1] == "--devices" {
+        for name in audio_stream::output_devices() {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+    if args[1] == "--render" {
+        render(&args[2..]);
+        return Ok(());
+    }
+    #[cfg(feature = "soak")]
+    {
+        if args[1] == "--soak" {
+            soak::run(&args[2..]);
+            return Ok(());
+        }
+    }
+    #[cfg(feature = "trace")]
+    let _trace = trace::start();
     // initialize gc
     let gc = Collector::new();
 
     // Create the sample player and controller
     let (mut player, mut controller) = sample_player(&gc);
 
-    // initialize state and begin the stream...
-    let _stream = audio_stream(move |mut context| {
+    // initialize state and begin the stream, rendering in blocks of `$PLAY_BLOCK_SIZE` frames
+    // if it is set
+    let block_size = std::env::var("PLAY_BLOCK_SIZE")
+        .ok()
+        .and_then(|block_size| block_size.parse().ok());
+    let render = fixed_blocks(block_size, move |mut context| {
         player.advance(&mut context);
     });
+    #[cfg(feature = "jack")]
+    let stream = audio_stream::jack_stream(render).expect("failed to connect to JACK");
+    // `$PLAY_DEVICE` names the output to start on, otherwise the stream starts on the default
+    #[cfg(not(feature = "jack"))]
+    let stream = supervised_stream(std::env::var("PLAY_DEVICE").ok(), render);
     controller.load_file(&args[1]);
-    ui::run(gc, controller)
+    for path in &args[2..] {
+        if let Err(e) = controller.enqueue(path) {
+            println!("failed to queue {}: {}", path, e);
+        }
+    }
+    ui::run(gc, controller, stream.handle())
+}
+
+/// render a file through the player without a device, as fast as it will go
+fn render(args: &[String]) {
+    let (output, path) = match args {
+        [output, path] => (output, path),
+        _ => {
+            println!("usage is: `play --render <output> <path>`");
+            std::process::exit(1);
+        }
+    };
+    let gc = Collector::new();
+    let (mut player, mut controller) = sample_player(&gc);
+    if let Err(e) = controller.open(path) {
+        println!("failed to open {}: {}", path, e);
+        std::process::exit(1);
+    }
+    let sample_rate = controller.sample_rate().unwrap_or(48000.0);
+    let num_channels = controller.num_channels().unwrap_or(2);
+    let num_samples = controller.duration_samples().unwrap_or(0);
+    controller.set_end_of_file(EndOfFile::Stop);
+    controller.play();
+    controller.flush();
+    let mut stream = offline_stream(sample_rate, 512, num_channels, |mut context| {
+        player.advance(&mut context);
+    });
+    if let Err(e) = stream.render_to_file(output, num_samples) {
+        println!("failed to render {}: {}", output, e);
+        std::process::exit(1);
+    }
 }
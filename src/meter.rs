@@ -0,0 +1,112 @@
+use crate::utils::{self, db_to_gain};
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+/// the most channels that are metered
+pub const MAX_CHANNELS: usize = 32;
+
+/// how long a peak is held before it starts to fall, in seconds
+const HOLD_TIME: f64 = 1.5;
+
+/// how quickly the meters fall, in decibels per second
+const DECAY_RATE: f64 = 20.0;
+
+/// The levels of each output channel over one block, measured on the audio thread
+#[derive(Clone, Copy)]
+pub struct BlockLevels {
+    num_channels: usize,
+    frames: usize,
+    peak: [f32; MAX_CHANNELS],
+    sum_of_squares: [f32; MAX_CHANNELS],
+}
+
+impl BlockLevels {
+    /// measure a block of deinterleaved samples, with `frames` samples per channel
+    pub fn measure<'a>(frames: usize, channels: impl Iterator<Item = &'a [f32]>) -> Self {
+        let mut levels = Self {
+            num_channels: 0,
+            frames,
+            peak: [0.0; MAX_CHANNELS],
+            sum_of_squares: [0.0; MAX_CHANNELS],
+        };
+        for (channel, samples) in channels.take(MAX_CHANNELS).enumerate() {
+            levels.peak[channel] = utils::peak(samples);
+            levels.sum_of_squares[channel] = utils::sum_of_squares(samples);
+            levels.num_channels = channel + 1;
+        }
+        levels
+    }
+}
+
+/// The displayed level of one channel, as linear gains
+#[derive(Clone, Copy, Default)]
+pub struct ChannelLevel {
+    /// the peak level, falling at the decay rate
+    pub peak: f32,
+    /// the RMS level since the last update, falling at the decay rate
+    pub rms: f32,
+    /// the highest recent peak, held for a moment before falling
+    pub hold: f32,
+    /// how long the held peak has been held, in seconds
+    hold_age: f64,
+}
+
+/// The consumer side of the meters, which turns the levels of each block into levels that
+/// can be drawn, with peak hold and decay.
+pub struct LevelMeter {
+    rx: Consumer<BlockLevels>,
+    channels: Vec<ChannelLevel>,
+}
+
+/// create the channel the audio thread publishes levels through, and the meter reading it
+pub fn level_meter() -> (Producer<BlockLevels>, LevelMeter) {
+    let (tx, rx) = RingBuffer::new(256).split();
+    let meter = LevelMeter {
+        rx,
+        channels: vec![],
+    };
+    (tx, meter)
+}
+
+impl LevelMeter {
+    /// read the levels published since the last update, `elapsed` seconds ago
+    pub fn update(&mut self, elapsed: f64) {
+        let decay = db_to_gain(-(DECAY_RATE * elapsed) as f32);
+        for level in self.channels.iter_mut() {
+            level.peak = utils::flush_denormal(level.peak * decay);
+            level.rms = utils::flush_denormal(level.rms * decay);
+            level.hold_age += elapsed;
+            if level.hold_age > HOLD_TIME {
+                level.hold = utils::flush_denormal(level.hold * decay).max(level.peak);
+            }
+        }
+
+        let mut sum_of_squares = [0.0; MAX_CHANNELS];
+        let mut frames = 0;
+        while let Some(block) = self.rx.pop() {
+            if self.channels.len() < block.num_channels {
+                self.channels
+                    .resize(block.num_channels, ChannelLevel::default());
+            }
+            for channel in 0..block.num_channels {
+                let level = &mut self.channels[channel];
+                level.peak = level.peak.max(block.peak[channel]);
+                if level.peak >= level.hold {
+                    level.hold = level.peak;
+                    level.hold_age = 0.0;
+                }
+                sum_of_squares[channel] += block.sum_of_squares[channel];
+            }
+            frames += block.frames;
+        }
+        if frames > 0 {
+            for (level, sum) in self.channels.iter_mut().zip(sum_of_squares.iter()) {
+                level.rms = level.rms.max((sum / (frames as f32)).sqrt());
+            }
+        }
+    }
+
+    /// the displayed level of every channel that has been metered
+    pub fn levels(&self) -> &[ChannelLevel] {
+        &self.channels
+    }
+}
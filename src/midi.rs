@@ -0,0 +1,88 @@
+use crate::sample_player::{SamplePlayerController, VoiceId};
+use basedrop::Shared;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::sync::{Arc, Mutex};
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+
+/// Where in the loaded file each note starts playing
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NoteMap {
+    /// every note plays from the start of the file
+    FromStart,
+    /// the root note plays from the start and each note above it starts `length` seconds
+    /// later than the one below, so the keyboard plays successive slices of the file
+    Slices { root: u8, length: f64 },
+}
+
+impl NoteMap {
+    /// the position a note starts playing from, in seconds
+    fn position(self, note: u8) -> f64 {
+        match self {
+            NoteMap::FromStart => 0.0,
+            NoteMap::Slices { root, length } => (note.saturating_sub(root) as f64) * length,
+        }
+    }
+}
+
+/// An open connection to a MIDI input, which plays the loaded file on a new voice for every
+/// note on and stops it on the matching note off. The connection closes when this is dropped.
+pub struct MidiTrigger {
+    _connection: MidiInputConnection<[Option<VoiceId>; 128]>,
+}
+
+/// connect to the first MIDI input, sending the notes it plays to the controller
+pub fn connect(
+    controller: Arc<Mutex<SamplePlayerController>>,
+    map: NoteMap,
+) -> Result<MidiTrigger, String> {
+    let mut input = MidiInput::new("play").map_err(|e| e.to_string())?;
+    input.ignore(Ignore::All);
+    let ports = input.ports();
+    let port = ports.first().ok_or("no MIDI input found")?;
+    let name = input.port_name(port).map_err(|e| e.to_string())?;
+
+    // the voice playing each note, the callback runs on a thread owned by the MIDI driver so
+    // it can wait on the controller
+    let callback = move |_: u64, message: &[u8], voices: &mut [Option<VoiceId>; 128]| {
+        let (status, note, velocity) = match message {
+            [status, note, velocity] => (status & 0xf0, note & 0x7f, *velocity),
+            _ => return,
+        };
+        let mut controller = if let Ok(controller) = controller.lock() {
+            controller
+        } else {
+            return;
+        };
+        let voice = &mut voices[note as usize];
+        match status {
+            NOTE_ON if velocity > 0 => {
+                let file = if let Some(file) = &controller.file {
+                    Shared::clone(file)
+                } else {
+                    return;
+                };
+                if let Some(id) = voice.take() {
+                    controller.stop_voice(id);
+                }
+                let start = (map.position(note) * file.sample_rate) as usize;
+                *voice = Some(controller.trigger_note(&file, start, velocity));
+            }
+            // a note on with no velocity is a note off
+            NOTE_ON | NOTE_OFF => {
+                if let Some(id) = voice.take() {
+                    controller.stop_voice(id);
+                }
+            }
+            _ => (),
+        }
+    };
+    let connection = input
+        .connect(port, "play-input", callback, [None; 128])
+        .map_err(|e| e.to_string())?;
+    println!("playing notes from {}", name);
+    Ok(MidiTrigger {
+        _connection: connection,
+    })
+}
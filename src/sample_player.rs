@@ -1,10 +1,21 @@
 use crate::audio_file::AudioFile;
-use crate::audio_stream::PlaybackContext;
-use basedrop::{Collector, Handle, Shared};
+use crate::audio_stream::{InputMonitor, PlaybackContext};
+use crate::automation::Automation;
+use crate::envelope::{velocity_to_db, Envelope, EnvelopeState};
+use crate::layout::ChannelLayout;
+use crate::loudness::integrated_loudness;
+use crate::meter::{level_meter, BlockLevels, LevelMeter};
+use crate::undo::UndoStack;
+use crate::utils::{apply_gain_ramp, db_to_gain, mix_with_gain_ramp, SmoothedGain};
+use basedrop::{Collector, Handle, Owned, Shared};
 use ringbuf::{Consumer, Producer, RingBuffer};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
+#[derive(Clone, Copy)]
 enum PlayerState {
     Playing,
     Stopped,
@@ -12,108 +23,1152 @@ enum PlayerState {
 
 enum Message {
     Seek(f64),
+    SeekSamples(usize),
+    Nudge(i64),
+    /// move the playhead by frames while stopped, and play a short window from there
+    Step(i64),
+    /// move the playhead by blocks while stopped, and play the block from there
+    StepBlocks(i64),
     Scrub(f64),
     Play,
+    /// start playing when the stream reaches a time, in frames
+    PlayAt(u64),
     Stop,
     SetActive(usize, bool),
-    NewFile(Shared<AudioFile>),
+    SetGain(usize, f32),
+    SetMasterGain(f32),
+    SetFadeTime(f64),
+    /// replace an item in the playlist, or add it if the index is the end of the playlist
+    NewFile(usize, Shared<AudioFile>),
+    Enqueue(Shared<AudioFile>),
+    Next,
+    Previous,
+    /// start a new voice playing a file from a sample at a gain in decibels
+    Trigger {
+        id: VoiceId,
+        file: Shared<AudioFile>,
+        start: usize,
+        db: f32,
+        looping: bool,
+    },
+    StopVoice(VoiceId),
+    StopVoices,
+    SetVoiceGain(VoiceId, f32),
+    /// set the envelope of voices triggered from now on
+    SetEnvelope(Envelope),
+    /// set or clear the file compared against the loaded file
+    Compare(Option<Shared<AudioFile>>),
+    /// set the linear gains of the loaded file and the comparison file
+    SetSourceGains(f32, f32),
+    SetEndOfFile(EndOfFile),
+    SetChannelMap(Shared<ChannelMap>),
+    /// set or clear the gain curve applied to the transport
+    SetAutomation(Option<Shared<Automation>>),
+    /// start or stop mixing the input into the output
+    SetInput(Option<Owned<InputMonitor>>),
+    SetMonitorGain(f32),
+    /// enable or disable silencing channels that contain NaN or infinite samples
+    SetGuard(bool),
+    /// release the transport voice and the playlist
+    ClearFile,
+}
+
+/// Something that happened on the audio thread, sent to the controller
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PlayerEvent {
+    /// the transport started playing
+    Started,
+    /// the transport stopped, after its fade out
+    Stopped,
+    /// the playhead reached the end of the last file to be played
+    ReachedEnd,
+    /// the transport voice started playing the playlist item at an index
+    FileLoaded(usize),
+    /// the device skipped audio, so there was a dropout
+    Xrun,
+    /// the stream was rebuilt on another device after losing its own
+    DeviceChanged,
+    /// a message couldn't be carried out, and was ignored
+    Error(PlayerError),
+    /// an output channel contained NaN or infinite samples and was silenced, sent when the
+    /// guard first trips after a clean block
+    NonFinite(usize),
+}
+
+/// A message the player ignored because carrying it out would have panicked or allocated on
+/// the audio thread
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PlayerError {
+    /// a channel strip change named a channel the player doesn't have
+    NoSuchChannel(usize),
+    /// a file couldn't be added because the playlist is full
+    PlaylistFull,
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlayerError::NoSuchChannel(channel) => write!(f, "there is no channel {}", channel),
+            PlayerError::PlaylistFull => write!(f, "the playlist is full"),
+        }
+    }
+}
+
+/// Identifies messages that replace each other, of which only the latest needs to be sent
+#[derive(Clone, Copy, PartialEq)]
+enum Coalesce {
+    Seek,
+    Gain(usize),
+    MasterGain,
+    VoiceGain(VoiceId),
+    SourceGains,
+    MonitorGain,
+    FadeTime,
+}
+
+impl Message {
+    fn coalesce(&self) -> Option<Coalesce> {
+        match self {
+            Message::Seek(_) | Message::SeekSamples(_) | Message::Scrub(_) => Some(Coalesce::Seek),
+            Message::SetGain(channel, _) => Some(Coalesce::Gain(*channel)),
+            Message::SetMasterGain(_) => Some(Coalesce::MasterGain),
+            Message::SetVoiceGain(id, _) => Some(Coalesce::VoiceGain(*id)),
+            Message::SetSourceGains(_, _) => Some(Coalesce::SourceGains),
+            Message::SetMonitorGain(_) => Some(Coalesce::MonitorGain),
+            Message::SetFadeTime(_) => Some(Coalesce::FadeTime),
+            _ => None,
+        }
+    }
+}
+
+/// the most messages that can wait for space in the ring buffer before new messages are
+/// dropped
+const MAX_BACKLOG: usize = 256;
+
+/// What the transport does when the playhead reaches the end of the file
+#[derive(druid::Data, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EndOfFile {
+    /// stop with the playhead at the end
+    Stop,
+    /// stop with the playhead back at the start
+    Rewind,
+    /// play the file again from the start
+    Loop,
+    /// play the next file in the playlist, stopping after the last
+    Next,
+}
+
+impl EndOfFile {
+    pub const ALL: &'static [EndOfFile] = &[
+        EndOfFile::Stop,
+        EndOfFile::Rewind,
+        EndOfFile::Loop,
+        EndOfFile::Next,
+    ];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            EndOfFile::Stop => "Stop at end",
+            EndOfFile::Rewind => "Rewind at end",
+            EndOfFile::Loop => "Loop",
+            EndOfFile::Next => "Play next",
+        }
+    }
+}
+
+/// How the channels of a file are routed to the channels of the device
+#[derive(Clone, PartialEq, Debug)]
+pub enum ChannelMap {
+    /// channels play on the speakers they are meant for, assuming the layouts of the file
+    /// and the device from their channel counts, so stereo files play on the front left and
+    /// right of a surround device. Other counts play channel for channel on the first outputs,
+    /// dropping any channels the device doesn't have.
+    Auto,
+    /// the gain from each file channel to each output, indexed `[output][input]`, with any
+    /// missing entries silent
+    Matrix(Vec<Vec<f32>>),
+}
+
+impl ChannelMap {
+    /// the gain from a channel of a file with `num_inputs` channels to an output of a device
+    /// with `num_outputs`
+    pub fn gain(&self, input: usize, num_inputs: usize, output: usize, num_outputs: usize) -> f32 {
+        match self {
+            ChannelMap::Auto => {
+                let outputs = ChannelLayout::from_channels(num_outputs);
+                ChannelLayout::from_channels(num_inputs).gain(input, outputs, output)
+            }
+            ChannelMap::Matrix(gains) => gains
+                .get(output)
+                .and_then(|row| row.get(input))
+                .copied()
+                .unwrap_or(0.0),
+        }
+    }
+}
+
+/// The approximate memory held by the controller's files, in bytes
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct MemoryUsage {
+    /// the files in the playlist and the loaded file, each counted once
+    pub files: usize,
+    /// previous versions of the loaded file, kept to undo edits
+    pub history: usize,
+    /// the file the loaded file is compared against
+    pub comparison: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.files + self.history + self.comparison
+    }
+}
+
+/// Identifies a voice started by [`SamplePlayerController::trigger`]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VoiceId(u64);
+
+/// A short gain ramp applied around transport changes, so that starting, stopping, and
+/// seeking don't click.
+#[derive(Clone, Copy)]
+struct Fade {
+    /// length of a complete fade, in seconds
+    time: f64,
+    /// the current fade gain, between 0 and 1
+    gain: f32,
+    fading_out: bool,
+    /// stop once the fade out completes
+    stop_pending: bool,
+    /// move the playhead here once the fade out completes, then fade back in
+    seek_pending: Option<usize>,
+}
+
+impl Fade {
+    fn new(time: f64) -> Self {
+        Self {
+            time,
+            gain: 0.0,
+            fading_out: false,
+            stop_pending: false,
+            seek_pending: None,
+        }
+    }
+
+    /// true if the fade time is zero, so that fades are skipped
+    fn is_instant(&self) -> bool {
+        self.time <= 0.0
+    }
+
+    /// the fade gain at the start and end of a block of `frames` samples. Without a fade time
+    /// the gain jumps to where it is headed at the start of the block.
+    fn ramp(&self, frames: usize, sample_rate: f64) -> (f32, f32) {
+        if self.is_instant() {
+            let end = if self.fading_out { 0.0 } else { 1.0 };
+            return (end, end);
+        }
+        let step = ((frames as f64) / (self.time * sample_rate)) as f32;
+        let end = if self.fading_out {
+            (self.gain - step).max(0.0)
+        } else {
+            (self.gain + step).min(1.0)
+        };
+        (self.gain, end)
+    }
+
+    fn fade_out(&mut self) {
+        self.fading_out = true;
+    }
+
+    fn fade_in(&mut self) {
+        self.fading_out = false;
+        self.stop_pending = false;
+        self.seek_pending = None;
+    }
+
+    fn is_silent(&self) -> bool {
+        self.fading_out && self.gain <= 0.0
+    }
+}
+
+/// Transport state published by the audio thread at the end of every block, which can be read
+/// from any thread without locking.
+pub struct Transport {
+    playhead: AtomicUsize,
+    num_samples: AtomicUsize,
+    playlist_index: AtomicUsize,
+    stream_time: AtomicU64,
+    output_latency: AtomicUsize,
+    is_playing: AtomicBool,
+    end_of_file: AtomicBool,
+}
+
+impl Transport {
+    fn new() -> Self {
+        Self {
+            playhead: AtomicUsize::new(0),
+            num_samples: AtomicUsize::new(0),
+            playlist_index: AtomicUsize::new(0),
+            stream_time: AtomicU64::new(0),
+            output_latency: AtomicUsize::new(0),
+            is_playing: AtomicBool::new(false),
+            end_of_file: AtomicBool::new(false),
+        }
+    }
+
+    /// the position of the playhead, in samples
+    pub fn playhead(&self) -> usize {
+        self.playhead.load(Ordering::SeqCst)
+    }
+
+    /// the length of the file being played, in samples
+    pub fn num_samples(&self) -> usize {
+        self.num_samples.load(Ordering::SeqCst)
+    }
+
+    /// the index of the playlist item being played
+    pub fn playlist_index(&self) -> usize {
+        self.playlist_index.load(Ordering::SeqCst)
+    }
+
+    /// the stream time at the end of the last block, in frames of the device clock since
+    /// the stream started
+    pub fn stream_time(&self) -> u64 {
+        self.stream_time.load(Ordering::SeqCst)
+    }
+
+    /// the output latency the device reported for the last block, in frames
+    pub fn output_latency(&self) -> usize {
+        self.output_latency.load(Ordering::SeqCst)
+    }
+
+    /// the position of the playhead as a fraction of the file length
+    pub fn position(&self) -> f64 {
+        let num_samples = self.num_samples();
+        if num_samples == 0 {
+            0.0
+        } else {
+            (self.playhead() as f64) / (num_samples as f64)
+        }
+    }
+
+    /// true if the player is producing audio, including while fading out
+    pub fn is_playing(&self) -> bool {
+        self.is_playing.load(Ordering::SeqCst)
+    }
+
+    /// true if playback stopped because the playhead reached the end of the file
+    pub fn end_of_file(&self) -> bool {
+        self.end_of_file.load(Ordering::SeqCst)
+    }
+
+    fn set_playhead(&self, playhead: usize) {
+        self.playhead.store(playhead, Ordering::SeqCst);
+    }
+}
+
+/// the most voices that can be triggered at once, not counting the transport voice
+const MAX_VOICES: usize = 32;
+
+/// the most files that can be queued in the playlist
+const MAX_PLAYLIST: usize = 1024;
+
+/// how much is played after stepping the playhead by frames, in seconds
+const STEP_WINDOW: f64 = 0.01;
+
+/// the most frames mixed with one gain ramp while following an automation curve, which is
+/// short enough that the ramp stays close to the curve multiplied by the fades
+const AUTOMATION_CHUNK: usize = 32;
+
+/// One instance of a file being played, with its own playhead, gain, and fades. The player
+/// mixes every voice into the output.
+struct Voice {
+    id: VoiceId,
+    file: Shared<AudioFile>,
+    playhead: usize,
+    gain: SmoothedGain,
+    fade: Fade,
+    state: PlayerState,
+    /// start again from the beginning when the end of the file is reached
+    looping: bool,
+    /// switch to this file once the fade out completes
+    file_pending: Option<Shared<AudioFile>>,
+    /// the envelope of a triggered voice
+    envelope: Option<EnvelopeState>,
+    /// stop after playing this many more frames
+    remaining: Option<usize>,
+}
+
+impl Voice {
+    /// create a stopped voice at the start of a file
+    fn new(id: VoiceId, file: Shared<AudioFile>, fade_time: f64) -> Self {
+        Self {
+            id,
+            file,
+            playhead: 0,
+            gain: SmoothedGain::new(1.0),
+            fade: Fade::new(fade_time),
+            state: PlayerState::Stopped,
+            looping: false,
+            file_pending: None,
+            envelope: None,
+            remaining: None,
+        }
+    }
+
+    /// create a voice that starts playing immediately. Voices starting at the beginning of the
+    /// file don't fade in, so that the transient of the file is kept intact.
+    fn triggered(
+        id: VoiceId,
+        file: Shared<AudioFile>,
+        start: usize,
+        gain: f32,
+        looping: bool,
+        fade_time: f64,
+    ) -> Self {
+        let mut voice = Self::new(id, file, fade_time);
+        voice.playhead = start.min(voice.file.num_samples);
+        voice.gain = SmoothedGain::new(gain);
+        voice.looping = looping;
+        voice.fade.gain = if start == 0 { 1.0 } else { 0.0 };
+        voice.state = PlayerState::Playing;
+        voice
+    }
+
+    fn is_playing(&self) -> bool {
+        matches!(self.state, PlayerState::Playing)
+    }
+
+    fn play(&mut self) {
+        if let PlayerState::Stopped = self.state {
+            self.fade.gain = 0.0;
+        }
+        self.fade.fade_in();
+        self.state = PlayerState::Playing;
+    }
+
+    /// release the envelope, or fade out if it has no release, or stop at once if there is no
+    /// fade time either
+    fn stop(&mut self) {
+        if let PlayerState::Playing = self.state {
+            match &mut self.envelope {
+                Some(envelope) if envelope.has_release() => envelope.release(),
+                _ if self.fade.is_instant() => self.state = PlayerState::Stopped,
+                _ => {
+                    self.fade.stop_pending = true;
+                    self.fade.fade_out();
+                }
+            }
+        }
+    }
+
+    /// move the playhead to a sample, fading out and back in if playing
+    fn seek_to(&mut self, sample: usize) {
+        let sample = sample.min(self.file.num_samples);
+        if matches!(self.state, PlayerState::Stopped) || self.fade.is_instant() {
+            self.playhead = sample;
+        } else {
+            self.fade.seek_pending = Some(sample);
+            self.fade.fade_out();
+        }
+    }
+
+    /// start playing another file from its beginning, fading out and back in if playing
+    fn switch_to(&mut self, file: Shared<AudioFile>) {
+        if matches!(self.state, PlayerState::Stopped) || self.fade.is_instant() {
+            self.file = file;
+            self.playhead = 0;
+        } else {
+            self.file_pending = Some(file);
+            self.fade.seek_pending = Some(0);
+            self.fade.fade_out();
+        }
+    }
+
+    /// take the playhead, state, and fades of another voice, so that both play the same
+    /// position of their files
+    fn follow(&mut self, leader: &Voice) {
+        self.playhead = leader.playhead;
+        self.state = leader.state;
+        self.fade = leader.fade;
+    }
+
+    /// move the playhead relative to where it is headed
+    fn nudge(&mut self, frames: i64) {
+        let from = self.fade.seek_pending.unwrap_or(self.playhead) as i64;
+        self.seek_to(from.saturating_add(frames).max(0) as usize);
+    }
+
+    /// mix the file into the output from frame `from` to the end of the block, following a
+    /// gain curve over the file if there is one. If the end of a file that doesn't loop is
+    /// reached the voice stops and the frame it stopped at is returned, leaving the fade and
+    /// gain ramps for whatever plays the rest of the block.
+    fn render(
+        &mut self,
+        context: &mut PlaybackContext,
+        from: usize,
+        map: &ChannelMap,
+        automation: Option<&Automation>,
+    ) -> Option<usize> {
+        if let PlayerState::Stopped = self.state {
+            return None;
+        }
+        let frames = context.buffer_size;
+        let (fade0, fade1) = self.fade.ramp(frames, context.sample_rate);
+        let (gain0, gain1) = self.gain.ramp();
+        let envelope = self
+            .envelope
+            .map(|envelope| envelope.next(frames, context.sample_rate));
+        let env0 = self.envelope.map_or(1.0, |envelope| envelope.gain());
+        let env1 = envelope.map_or(1.0, |envelope| envelope.gain());
+        let (start, end) = (gain0 * fade0 * env0, gain1 * fade1 * env1);
+        let gain_at = |frame: usize| start + (end - start) * (frame as f32) / (frames as f32);
+
+        let num_samples = self.file.num_samples;
+        let num_inputs = self.file.num_channels;
+        let sample_rate = self.file.sample_rate;
+        let mut offset = from;
+        while offset < frames {
+            // the frames left in the file, which may be fewer than are left in the block or
+            // none at all if the playhead was moved past the end of a shorter file
+            let mut len = num_samples
+                .saturating_sub(self.playhead)
+                .min(frames - offset)
+                .min(self.remaining.unwrap_or(frames));
+            let (mut auto0, mut auto1) = (1.0, 1.0);
+            if let Some(automation) = automation {
+                // end the chunk on the next breakpoint, so it lands on its exact sample
+                let time = (self.playhead as f64) / sample_rate;
+                if let Some(next) = automation.next_point(time) {
+                    let until =
+                        ((next * sample_rate).ceil() as usize).saturating_sub(self.playhead);
+                    len = len.min(until.max(1));
+                }
+                len = len.min(AUTOMATION_CHUNK);
+                auto0 = automation.gain(time);
+                auto1 = automation.gain(((self.playhead + len) as f64) / sample_rate);
+            }
+            if len > 0 {
+                let (gain0, gain1) = (gain_at(offset) * auto0, gain_at(offset + len) * auto1);
+                for output in 0..context.num_channels {
+                    for input in 0..num_inputs {
+                        let gain = map.gain(input, num_inputs, output, context.num_channels);
+                        if gain == 0.0 {
+                            continue;
+                        }
+                        let samples =
+                            &self.file.get_channel(input)[self.playhead..self.playhead + len];
+                        let out = &mut context.get_output(output)[offset..offset + len];
+                        mix_with_gain_ramp(samples, out, gain0 * gain, gain1 * gain);
+                    }
+                }
+            }
+            offset += len;
+            self.playhead += len;
+            if let Some(remaining) = &mut self.remaining {
+                *remaining -= len;
+                if *remaining == 0 {
+                    self.state = PlayerState::Stopped;
+                    break;
+                }
+            }
+            if self.playhead >= num_samples {
+                if self.looping && num_samples > 0 {
+                    self.playhead = 0;
+                } else {
+                    self.state = PlayerState::Stopped;
+                    return Some(offset);
+                }
+            }
+        }
+        self.fade.gain = fade1;
+        self.gain.snap();
+        self.envelope = envelope;
+        if envelope.map_or(false, |envelope| envelope.is_done()) {
+            self.state = PlayerState::Stopped;
+        }
+        self.complete_fade();
+        None
+    }
+
+    /// perform the transport change that was waiting on a fade out, if it has finished
+    fn complete_fade(&mut self) {
+        if !self.fade.is_silent() {
+            return;
+        }
+        if let Some(file) = self.file_pending.take() {
+            self.file = file;
+        }
+        if let Some(sample) = self.fade.seek_pending.take() {
+            self.playhead = sample.min(self.file.num_samples);
+        }
+        if self.fade.stop_pending {
+            self.state = PlayerState::Stopped;
+        }
+        self.fade.fade_in();
+    }
 }
 
 pub struct SamplePlayer {
-    pub file: Option<Shared<AudioFile>>,
+    /// the voice playing the loaded file, driven by the transport
+    transport_voice: Option<Voice>,
+    /// voices started by triggers, mixed over the transport voice
+    voices: Vec<Voice>,
+    /// files played one after another by the transport voice
+    playlist: Vec<Shared<AudioFile>>,
+    /// the index of the playlist item being played
+    current: usize,
+    /// a voice following the transport voice, playing the file it is compared against
+    comparison: Option<Voice>,
+    /// the gains of the transport voice and the comparison voice
+    source_gains: (f32, f32),
+    /// the envelope of newly triggered voices
+    envelope: Envelope,
+    /// the gain curve followed by the transport voice and the comparison voice
+    automation: Option<Shared<Automation>>,
+    /// the stream time the transport voice starts playing at
+    start_at: Option<u64>,
+    /// the length of the last block, which the playhead steps by
+    block_size: usize,
+    end_of_file: EndOfFile,
+    channel_map: Shared<ChannelMap>,
+    /// silence output channels that contain NaN or infinite samples
+    guard: bool,
+    /// true if the guard silenced a channel in the last block
+    guard_tripped: bool,
     active: [bool; 32],
-    playhead: Arc<AtomicUsize>,
-    state: PlayerState,
+    gain: [SmoothedGain; 32],
+    master_gain: SmoothedGain,
+    /// the input mixed into the output, if it is being monitored
+    input: Option<Owned<InputMonitor>>,
+    monitor_gain: SmoothedGain,
+    fade_time: f64,
+    transport: Arc<Transport>,
+    /// the levels of every block, after gains are applied
+    levels: Producer<BlockLevels>,
+    events: Producer<PlayerEvent>,
     rx: Consumer<Message>,
 }
 
 pub struct SamplePlayerController {
     tx: Producer<Message>,
-    playhead: Arc<AtomicUsize>,
+    events: Consumer<PlayerEvent>,
+    transport: Arc<Transport>,
+    meter: Arc<Mutex<LevelMeter>>,
     collector: Handle,
     sample_rate: Option<f64>,
     num_channels: Option<usize>,
     num_samples: Option<usize>,
     pub file: Option<Shared<AudioFile>>,
+    /// where the loaded file was opened from or last saved to
+    path: Option<String>,
+    /// previous versions of the loaded file
+    history: UndoStack<Shared<AudioFile>>,
+    next_voice: u64,
+    /// how much the velocity of a note changes its gain, from 0 to 1
+    velocity_sensitivity: f32,
+    /// the files queued in the player, with the paths they were opened from
+    playlist: Vec<(String, Shared<AudioFile>)>,
+    /// the playlist item the loaded file belongs to
+    current: usize,
+    /// the file compared against the loaded file, with its loudness
+    comparison: Option<(Shared<AudioFile>, Option<f64>)>,
+    /// the loudness of the loaded file, measured when it is first needed
+    loudness: Option<Option<f64>>,
+    listening_to_b: bool,
+    /// attenuate the louder of the compared files to the loudness of the quieter
+    match_loudness: bool,
+    /// messages waiting for space in the ring buffer
+    backlog: VecDeque<Message>,
+    dropped: usize,
 }
 
 /// create a new sample player and its controller
 pub fn sample_player(c: &Collector) -> (SamplePlayer, SamplePlayerController) {
-    let playhead = Arc::new(AtomicUsize::new(0));
+    let transport = Arc::new(Transport::new());
     let (tx, rx) = RingBuffer::new(2048).split();
+    let (levels, meter) = level_meter();
+    let (event_tx, event_rx) = RingBuffer::new(256).split();
     (
         SamplePlayer {
-            file: None,
+            transport_voice: None,
+            voices: Vec::with_capacity(MAX_VOICES),
+            playlist: Vec::with_capacity(MAX_PLAYLIST),
+            current: 0,
+            comparison: None,
+            source_gains: (1.0, 0.0),
+            envelope: Envelope::default(),
+            automation: None,
+            start_at: None,
+            block_size: 512,
+            end_of_file: EndOfFile::Next,
+            channel_map: Shared::new(&c.handle(), ChannelMap::Auto),
+            guard: true,
+            guard_tripped: false,
             active: [true; 32],
-            playhead: playhead.clone(),
-            state: PlayerState::Stopped,
+            gain: [SmoothedGain::new(1.0); 32],
+            master_gain: SmoothedGain::new(1.0),
+            input: None,
+            monitor_gain: SmoothedGain::new(1.0),
+            fade_time: 0.005,
+            transport: transport.clone(),
+            levels,
+            events: event_tx,
             rx,
         },
         SamplePlayerController {
             tx,
-            playhead: playhead.clone(),
+            events: event_rx,
+            transport,
+            meter: Arc::new(Mutex::new(meter)),
             collector: c.handle(),
             sample_rate: None,
             num_channels: None,
             num_samples: None,
             file: None,
+            path: None,
+            history: UndoStack::new(512 << 20),
+            next_voice: 0,
+            velocity_sensitivity: 1.0,
+            playlist: vec![],
+            current: 0,
+            comparison: None,
+            loudness: None,
+            listening_to_b: false,
+            match_loudness: true,
+            backlog: VecDeque::new(),
+            dropped: 0,
         },
     )
 }
 
 impl SamplePlayer {
     pub fn playhead(&self) -> usize {
-        self.playhead.load(Ordering::SeqCst)
+        self.transport.playhead()
     }
 
     #[inline]
     pub fn advance(&mut self, context: &mut PlaybackContext) {
-        while let Some(msg) = self.rx.pop() {
-            match msg {
-                Message::Seek(pos) => {
-                    if let Some(f) = &self.file {
-                        self.playhead.store(
-                            ((f.sample_rate * pos) as usize).min(f.num_samples),
-                            Ordering::SeqCst,
-                        );
+        if context.xrun {
+            self.emit(PlayerEvent::Xrun);
+        }
+        if context.device_changed {
+            self.emit(PlayerEvent::DeviceChanged);
+        }
+        self.block_size = context.buffer_size;
+        {
+            span!("handle messages");
+            while let Some(msg) = self.rx.pop() {
+                self.handle_message(msg);
+            }
+        }
+
+        // a scheduled start that falls in this block, or was missed, starts on its frame
+        let mut from = 0;
+        if let Some(start_at) = self.start_at {
+            if start_at < context.stream_time + context.buffer_size as u64 {
+                from = start_at.saturating_sub(context.stream_time) as usize;
+                self.start_at = None;
+                if let Some(voice) = &mut self.transport_voice {
+                    voice.play();
+                }
+            }
+        }
+        let start = from;
+
+        if let (Some(comparison), Some(voice)) = (&mut self.comparison, &self.transport_voice) {
+            comparison.follow(voice);
+        }
+        // hand off to the next item in the playlist on the frame the previous one ends
+        while let Some(voice) = &mut self.transport_voice {
+            let automation = self.automation.as_deref();
+            let rendered = voice.render(context, from, &self.channel_map, automation);
+            let end = if let Some(end) = rendered {
+                end
+            } else {
+                break;
+            };
+            let next = self.playlist.get(self.current + 1);
+            match (self.end_of_file, next) {
+                (EndOfFile::Next, Some(next)) => {
+                    voice.file = Shared::clone(next);
+                    voice.playhead = 0;
+                    voice.state = PlayerState::Playing;
+                    self.current += 1;
+                    from = end;
+                    let _ = self.events.push(PlayerEvent::FileLoaded(self.current));
+                }
+                (EndOfFile::Rewind, _) => {
+                    voice.playhead = 0;
+                    self.reached_end();
+                    break;
+                }
+                _ => {
+                    self.reached_end();
+                    break;
+                }
+            }
+        }
+        if let Some(comparison) = &mut self.comparison {
+            let automation = self.automation.as_deref();
+            comparison.render(context, start, &self.channel_map, automation);
+        }
+        let mut index = 0;
+        while index < self.voices.len() {
+            let voice = &mut self.voices[index];
+            voice.render(context, 0, &self.channel_map, None);
+            if voice.is_playing() {
+                index += 1;
+            } else {
+                self.voices.remove(index);
+            }
+        }
+
+        for channel in 0..context.num_channels.min(self.active.len()) {
+            let output = context.get_output(channel);
+            if self.active[channel] {
+                let (gain0, gain1) = self.gain[channel].ramp();
+                let (master0, master1) = self.master_gain.ramp();
+                apply_gain_ramp(output, gain0 * master0, gain1 * master1);
+            } else {
+                for sample in output.iter_mut() {
+                    *sample = 0.0;
+                }
+            }
+        }
+        if let Some(input) = &mut self.input {
+            let (gain0, gain1) = self.monitor_gain.ramp();
+            input.mix(context, gain0, gain1);
+        }
+        if self.guard {
+            self.guard_output(context);
+        }
+        self.snap_gains();
+        self.transport.stream_time.store(
+            context.stream_time + context.buffer_size as u64,
+            Ordering::SeqCst,
+        );
+        self.transport
+            .output_latency
+            .store(context.output_latency, Ordering::SeqCst);
+        self.publish();
+        // if the meters aren't being read the levels are dropped
+        let _ = self.levels.push(BlockLevels::measure(
+            context.buffer_size,
+            context.channels(),
+        ));
+    }
+
+    fn handle_message(&mut self, msg: Message) {
+        match msg {
+            Message::Seek(pos) => {
+                if let Some(voice) = &mut self.transport_voice {
+                    voice.seek_to((voice.file.sample_rate * pos) as usize);
+                    self.transport.end_of_file.store(false, Ordering::SeqCst);
+                }
+            }
+            Message::SeekSamples(sample) => {
+                if let Some(voice) = &mut self.transport_voice {
+                    voice.seek_to(sample);
+                    self.transport.end_of_file.store(false, Ordering::SeqCst);
+                }
+            }
+            Message::Nudge(frames) => {
+                if let Some(voice) = &mut self.transport_voice {
+                    voice.nudge(frames);
+                    self.transport.end_of_file.store(false, Ordering::SeqCst);
+                }
+            }
+            Message::Step(frames) => {
+                let window = self
+                    .transport_voice
+                    .as_ref()
+                    .map_or(0, |voice| (voice.file.sample_rate * STEP_WINDOW) as usize);
+                self.step(frames, window.max(1));
+            }
+            Message::StepBlocks(blocks) => {
+                let frames = blocks.saturating_mul(self.block_size as i64);
+                self.step(frames, self.block_size);
+            }
+            Message::NewFile(index, file) => {
+                if index < self.playlist.len() {
+                    self.playlist[index] = Shared::clone(&file);
+                } else if self.playlist.len() < MAX_PLAYLIST {
+                    self.playlist.push(Shared::clone(&file));
+                } else {
+                    self.emit(PlayerEvent::Error(PlayerError::PlaylistFull));
+                    return;
+                }
+                if index != self.current {
+                    return;
+                }
+                self.emit(PlayerEvent::FileLoaded(index));
+                match &mut self.transport_voice {
+                    Some(voice) => {
+                        voice.playhead = voice.playhead.min(file.num_samples);
+                        voice.file = file;
+                    }
+                    None => {
+                        let mut voice = Voice::new(VoiceId(0), file, self.fade_time);
+                        voice.gain = SmoothedGain::new(self.source_gains.0);
+                        voice.looping = self.end_of_file == EndOfFile::Loop;
+                        self.transport_voice = Some(voice);
+                    }
+                }
+            }
+            Message::Enqueue(file) => {
+                if self.playlist.len() < MAX_PLAYLIST {
+                    self.playlist.push(file);
+                } else {
+                    self.emit(PlayerEvent::Error(PlayerError::PlaylistFull));
+                }
+            }
+            Message::Next => self.select(self.current + 1),
+            Message::Previous => {
+                if self.current > 0 {
+                    self.select(self.current - 1);
+                } else if let Some(voice) = &mut self.transport_voice {
+                    voice.seek_to(0);
+                }
+            }
+            Message::Scrub(_) => {
+                //todo...
+            }
+            Message::SetActive(channel, active) => match self.active.get_mut(channel) {
+                Some(flag) => *flag = active,
+                None => self.emit(PlayerEvent::Error(PlayerError::NoSuchChannel(channel))),
+            },
+            Message::SetGain(channel, db) => match self.gain.get_mut(channel) {
+                Some(gain) => gain.set_db(db),
+                None => self.emit(PlayerEvent::Error(PlayerError::NoSuchChannel(channel))),
+            },
+            Message::SetMasterGain(db) => {
+                self.master_gain.set_db(db);
+            }
+            Message::SetFadeTime(seconds) => {
+                self.fade_time = seconds;
+                let voices = self
+                    .transport_voice
+                    .iter_mut()
+                    .chain(self.comparison.iter_mut())
+                    .chain(self.voices.iter_mut());
+                for voice in voices {
+                    voice.fade.time = seconds;
+                }
+            }
+            Message::Play => {
+                self.start_at = None;
+                if let Some(voice) = &mut self.transport_voice {
+                    voice.play();
+                }
+            }
+            Message::PlayAt(stream_time) => {
+                self.start_at = Some(stream_time);
+            }
+            Message::Stop => {
+                self.start_at = None;
+                if let Some(voice) = &mut self.transport_voice {
+                    voice.stop();
+                }
+            }
+            Message::Trigger {
+                id,
+                file,
+                start,
+                db,
+                looping,
+            } => {
+                // steal the oldest voice rather than growing the list on the audio thread
+                if self.voices.len() >= MAX_VOICES {
+                    self.voices.remove(0);
+                }
+                let gain = db_to_gain(db);
+                let mut voice = Voice::triggered(id, file, start, gain, looping, self.fade_time);
+                voice.envelope = Some(EnvelopeState::new(self.envelope));
+                self.voices.push(voice);
+            }
+            Message::StopVoice(id) => {
+                if let Some(voice) = self.voices.iter_mut().find(|voice| voice.id == id) {
+                    voice.stop();
+                }
+            }
+            Message::StopVoices => {
+                for voice in self.voices.iter_mut() {
+                    voice.stop();
+                }
+            }
+            Message::SetVoiceGain(id, db) => {
+                if let Some(voice) = self.voices.iter_mut().find(|voice| voice.id == id) {
+                    voice.gain.set_db(db);
+                }
+            }
+            Message::SetEnvelope(envelope) => {
+                self.envelope = envelope;
+            }
+            Message::Compare(file) => {
+                self.comparison = file.map(|file| {
+                    let mut voice = Voice::new(VoiceId(0), file, self.fade_time);
+                    voice.gain = SmoothedGain::new(self.source_gains.1);
+                    voice
+                });
+            }
+            Message::ClearFile => {
+                // let the transport voice fade out with the triggered voices, which releases it
+                // once it has stopped
+                if let Some(mut voice) = self.transport_voice.take() {
+                    voice.stop();
+                    if voice.is_playing() {
+                        // steal the oldest voice, as a trigger does, so the fade out always
+                        // finishes
+                        if self.voices.len() >= MAX_VOICES {
+                            self.voices.remove(0);
+                        }
+                        self.voices.push(voice);
                     }
                 }
-                Message::NewFile(file) => {
-                    self.file = Some(file);
+                if let Some(comparison) = &mut self.comparison {
+                    comparison.stop();
                 }
-                Message::Scrub(_) => {
-                    //todo...
+                self.playlist.clear();
+                self.current = 0;
+                self.start_at = None;
+                self.transport.end_of_file.store(false, Ordering::SeqCst);
+            }
+            Message::SetChannelMap(map) => {
+                self.channel_map = map;
+            }
+            Message::SetAutomation(automation) => {
+                self.automation = automation;
+            }
+            Message::SetInput(input) => {
+                self.input = input;
+            }
+            Message::SetMonitorGain(db) => {
+                self.monitor_gain.set_db(db);
+            }
+            Message::SetGuard(guard) => {
+                self.guard = guard;
+            }
+            Message::SetEndOfFile(end_of_file) => {
+                self.end_of_file = end_of_file;
+                if let Some(voice) = &mut self.transport_voice {
+                    voice.looping = end_of_file == EndOfFile::Loop;
                 }
-                Message::SetActive(channel, active) => {
-                    self.active[channel] = active;
+            }
+            Message::SetSourceGains(a, b) => {
+                self.source_gains = (a, b);
+                if let Some(voice) = &mut self.transport_voice {
+                    voice.gain.set(a);
+                }
+                if let Some(voice) = &mut self.comparison {
+                    voice.gain.set(b);
                 }
-                Message::Play => self.state = PlayerState::Playing,
-                Message::Stop => self.state = PlayerState::Stopped,
             }
         }
+    }
 
-        if let PlayerState::Stopped = self.state {
+    /// send an event to the controller, which is dropped if the controller has fallen behind
+    fn emit(&mut self, event: PlayerEvent) {
+        let _ = self.events.push(event);
+    }
+
+    fn reached_end(&mut self) {
+        self.transport.end_of_file.store(true, Ordering::SeqCst);
+        self.emit(PlayerEvent::ReachedEnd);
+    }
+
+    /// move the transport playhead, and if it is stopped play `window` frames from the new
+    /// position on a voice of their own, without fades so that clicks can be heard
+    fn step(&mut self, frames: i64, window: usize) {
+        let voice = if let Some(voice) = &mut self.transport_voice {
+            voice
+        } else {
+            return;
+        };
+        voice.nudge(frames);
+        self.transport.end_of_file.store(false, Ordering::SeqCst);
+        if voice.is_playing() {
+            return;
+        }
+        let file = Shared::clone(&voice.file);
+        let gain = self.source_gains.0;
+        let mut audition = Voice::triggered(VoiceId(0), file, voice.playhead, gain, false, 0.0);
+        audition.fade.gain = 1.0;
+        audition.remaining = Some(window);
+        if self.voices.len() >= MAX_VOICES {
+            self.voices.remove(0);
+        }
+        self.voices.push(audition);
+    }
+
+    /// switch the transport voice to the start of an item in the playlist
+    fn select(&mut self, index: usize) {
+        let file = if let Some(file) = self.playlist.get(index) {
+            Shared::clone(file)
+        } else {
             return;
+        };
+        self.current = index;
+        self.transport.end_of_file.store(false, Ordering::SeqCst);
+        if let Some(voice) = &mut self.transport_voice {
+            voice.switch_to(file);
         }
+        self.emit(PlayerEvent::FileLoaded(index));
+    }
 
-        if let Some(file) = &self.file {
-            if self.playhead() >= file.num_samples {
-                self.state = PlayerState::Stopped;
-                return;
+    /// publish the state of the transport voice for other threads
+    fn publish(&mut self) {
+        self.transport
+            .playlist_index
+            .store(self.current, Ordering::SeqCst);
+        let is_playing = if let Some(voice) = &self.transport_voice {
+            self.transport.set_playhead(voice.playhead);
+            self.transport
+                .num_samples
+                .store(voice.file.num_samples, Ordering::SeqCst);
+            voice.is_playing()
+        } else {
+            self.transport.set_playhead(0);
+            self.transport.num_samples.store(0, Ordering::SeqCst);
+            false
+        };
+        if is_playing != self.transport.is_playing() {
+            self.emit(if is_playing {
+                PlayerEvent::Started
+            } else {
+                PlayerEvent::Stopped
+            });
+        }
+        self.transport
+            .is_playing
+            .store(is_playing, Ordering::SeqCst);
+    }
+
+    /// silence every output channel containing a sample that isn't finite, so that one bad
+    /// sample can't poison the device or whatever is downstream of it
+    fn guard_output(&mut self, context: &mut PlaybackContext) {
+        let mut tripped = None;
+        for (channel, output) in context.channels_mut().enumerate() {
+            if output.iter().all(|sample| sample.is_finite()) {
+                continue;
             }
-            for channel in 0..context.num_channels.max(file.num_channels) {
-                if !self.active[channel] {
-                    continue;
-                }
-                let start = channel * file.num_samples + self.playhead().min(file.num_samples);
-                let end = channel * file.num_samples
-                    + (self.playhead() + context.buffer_size).min(file.num_samples);
-                context.get_output(channel)[0..(end - start)]
-                    .copy_from_slice(&file.data[start..end]);
+            for sample in output.iter_mut() {
+                *sample = 0.0;
             }
-            self.playhead
-                .fetch_add(context.buffer_size, Ordering::SeqCst);
+            tripped = tripped.or(Some(channel));
+        }
+        if let (Some(channel), false) = (tripped, self.guard_tripped) {
+            self.emit(PlayerEvent::NonFinite(channel));
         }
+        self.guard_tripped = tripped.is_some();
+    }
+
+    /// complete any gain ramps started during this block
+    fn snap_gains(&mut self) {
+        for gain in self.gain.iter_mut() {
+            gain.snap();
+        }
+        self.master_gain.snap();
+        self.monitor_gain.snap();
     }
 }
 
@@ -128,21 +1183,101 @@ impl SamplePlayerController {
     pub fn num_channels(&self) -> Option<usize> {
         self.num_channels
     }
+    /// send a message to the player, or queue it if the ring buffer is full
     fn send_msg(&mut self, message: Message) {
-        let mut e = self.tx.push(message);
-        while let Err(message) = e {
-            e = self.tx.push(message);
+        if self.flush() == 0 {
+            if let Err(message) = self.tx.push(message) {
+                self.queue(message);
+            }
+        } else {
+            self.queue(message);
+        }
+    }
+    fn queue(&mut self, message: Message) {
+        // a queued seek or gain change is replaced by a newer one. It is only replaced in place
+        // if nothing was queued after it, otherwise the newer one moves to the back so that it
+        // still lands after the nudges, steps and file changes queued in between.
+        if let Some(key) = message.coalesce() {
+            let last = self
+                .backlog
+                .back_mut()
+                .filter(|queued| queued.coalesce() == Some(key));
+            if let Some(last) = last {
+                *last = message;
+                return;
+            }
+            self.backlog.retain(|queued| queued.coalesce() != Some(key));
+        }
+        if self.backlog.len() >= MAX_BACKLOG {
+            self.dropped += 1;
+            return;
         }
+        self.backlog.push_back(message);
+    }
+    /// send the messages that were queued while the ring buffer was full, returns the number
+    /// still waiting
+    pub fn flush(&mut self) -> usize {
+        while let Some(message) = self.backlog.pop_front() {
+            if let Err(message) = self.tx.push(message) {
+                self.backlog.push_front(message);
+                break;
+            }
+        }
+        self.backlog.len()
+    }
+    /// the next event from the player, if any have happened since the last call
+    pub fn poll_event(&mut self) -> Option<PlayerEvent> {
+        self.events.pop()
+    }
+    /// the number of messages dropped because the player fell too far behind
+    pub fn dropped_messages(&self) -> usize {
+        self.dropped
     }
     pub fn seek(&mut self, seconds: f64) {
         self.send_msg(Message::Seek(seconds));
     }
+    /// move the playhead to an exact sample
+    pub fn seek_samples(&mut self, sample: usize) {
+        self.send_msg(Message::SeekSamples(sample));
+    }
+    /// move the playhead forwards or backwards by a number of frames
+    pub fn nudge(&mut self, frames: i64) {
+        self.send_msg(Message::Nudge(frames));
+    }
+    /// move the playhead by frames while stopped, playing a few milliseconds from the new
+    /// position so that clicks and edit points can be found by ear
+    pub fn step(&mut self, frames: i64) {
+        self.send_msg(Message::Step(frames));
+    }
+    /// move the playhead by whole blocks while stopped, playing the block it lands on
+    pub fn step_blocks(&mut self, blocks: i64) {
+        self.send_msg(Message::StepBlocks(blocks));
+    }
     pub fn playhead(&self) -> usize {
-        self.playhead.load(Ordering::SeqCst)
+        self.transport.playhead()
+    }
+    /// the transport state published by the player
+    pub fn transport(&self) -> Arc<Transport> {
+        Arc::clone(&self.transport)
+    }
+    /// the peak and RMS levels of the output, published by the player
+    pub fn meter(&self) -> Arc<Mutex<LevelMeter>> {
+        Arc::clone(&self.meter)
     }
     pub fn play(&mut self) {
         self.send_msg(Message::Play);
     }
+    /// start playing when the stream reaches a time, in frames of the device clock since the
+    /// stream started, so that the first frame plays on that exact sample. A time that has
+    /// already passed starts playing on the next block.
+    pub fn play_at(&mut self, stream_time: u64) {
+        self.send_msg(Message::PlayAt(stream_time));
+    }
+    /// the time the player has rendered up to, in frames of the device clock since the
+    /// stream started
+    pub fn stream_time(&self) -> u64 {
+        self.transport.stream_time()
+    }
     pub fn stop(&mut self) {
         self.send_msg(Message::Stop);
     }
@@ -152,24 +1287,525 @@ impl SamplePlayerController {
     pub fn set_active(&mut self, channel_index: usize, active: bool) {
         self.send_msg(Message::SetActive(channel_index, active));
     }
+    /// set the length of the fades applied when starting, stopping, and seeking
+    pub fn set_fade_time(&mut self, seconds: f64) {
+        self.send_msg(Message::SetFadeTime(seconds));
+    }
+    /// set the gain of a channel, in decibels
+    pub fn set_gain(&mut self, channel_index: usize, db: f32) {
+        self.send_msg(Message::SetGain(channel_index, db));
+    }
+    /// set the gain applied to every channel, in decibels
+    pub fn set_master_gain(&mut self, db: f32) {
+        self.send_msg(Message::SetMasterGain(db));
+    }
+    /// open a file to be triggered over the loaded file
+    pub fn load_sample(&self, path: &str) -> Result<Shared<AudioFile>, hound::Error> {
+        Ok(Shared::new(&self.collector, AudioFile::open(path)?))
+    }
+    /// start playing a file on a new voice mixed over the loaded file, at a gain in decibels.
+    /// The same file can be triggered any number of times, up to 32 voices at once.
+    pub fn trigger(&mut self, file: &Shared<AudioFile>, db: f32, looping: bool) -> VoiceId {
+        self.trigger_from(file, 0, db, looping)
+    }
+    /// start playing a file on a new voice from a sample, fading in unless it is the first
+    pub fn trigger_from(
+        &mut self,
+        file: &Shared<AudioFile>,
+        start: usize,
+        db: f32,
+        looping: bool,
+    ) -> VoiceId {
+        self.next_voice += 1;
+        let id = VoiceId(self.next_voice);
+        self.send_msg(Message::Trigger {
+            id,
+            file: Shared::clone(file),
+            start,
+            db,
+            looping,
+        });
+        id
+    }
+    /// start playing a file on a new voice for a note, at a gain set by its velocity
+    pub fn trigger_note(
+        &mut self,
+        file: &Shared<AudioFile>,
+        start: usize,
+        velocity: u8,
+    ) -> VoiceId {
+        let db = velocity_to_db(velocity, self.velocity_sensitivity);
+        self.trigger_from(file, start, db, false)
+    }
+    /// set how much the velocity of a note changes its gain, 0 ignores velocity and 1 follows
+    /// the square of the velocity
+    pub fn set_velocity_sensitivity(&mut self, sensitivity: f32) {
+        self.velocity_sensitivity = sensitivity.clamp(0.0, 1.0);
+    }
+    /// set the envelope of voices triggered from now on
+    pub fn set_envelope(&mut self, envelope: Envelope) {
+        self.send_msg(Message::SetEnvelope(envelope));
+    }
+    /// release a triggered voice, which plays out its envelope or fades out
+    pub fn stop_voice(&mut self, id: VoiceId) {
+        self.send_msg(Message::StopVoice(id));
+    }
+    /// fade out and release every triggered voice
+    pub fn stop_voices(&mut self) {
+        self.send_msg(Message::StopVoices);
+    }
+    /// set the gain of a triggered voice, in decibels
+    pub fn set_voice_gain(&mut self, id: VoiceId, db: f32) {
+        self.send_msg(Message::SetVoiceGain(id, db));
+    }
     pub fn load_file(&mut self, s: &str) {
-        let audio_file = Shared::new(
-            &self.collector,
-            AudioFile::open(s).expect("file does not exist"),
-        );
+        let audio_file = AudioFile::open(s).expect("file does not exist");
+        self.load(s, audio_file);
+    }
+    /// replace the loaded file and the playlist with a file opened from a path, keeping them if
+    /// the file can't be opened
+    pub fn open(&mut self, path: &str) -> Result<(), hound::Error> {
+        let audio_file = AudioFile::open(path)?;
+        self.unload();
+        self.load(path, audio_file);
+        Ok(())
+    }
+    fn load(&mut self, path: &str, audio_file: AudioFile) {
+        let audio_file = Shared::new(&self.collector, audio_file);
+        self.history.clear();
+        self.set_path(path);
+        if self.playlist.is_empty() {
+            self.playlist
+                .push((path.to_owned(), Shared::clone(&audio_file)));
+        }
+        self.set_shared_file(audio_file);
+    }
+    /// stop playing and release the loaded file, its history, and the playlist, while the
+    /// stream keeps running
+    pub fn unload(&mut self) {
+        self.file = None;
+        self.path = None;
+        self.num_samples = None;
+        self.num_channels = None;
+        self.sample_rate = None;
+        self.loudness = None;
+        self.history.clear();
+        self.playlist.clear();
+        self.current = 0;
+        self.send_msg(Message::ClearFile);
+    }
+    /// add a file, or every .wav file in a folder in name order, to the end of the playlist
+    pub fn enqueue(&mut self, path: &str) -> Result<(), hound::Error> {
+        let path = Path::new(path);
+        if !path.is_dir() {
+            return self.enqueue_file(path);
+        }
+        let mut entries = std::fs::read_dir(path)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension().map_or(false, |ext| {
+                    ext.to_string_lossy().eq_ignore_ascii_case("wav")
+                })
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        for entry in entries {
+            self.enqueue_file(&entry)?;
+        }
+        Ok(())
+    }
+    fn enqueue_file(&mut self, path: &Path) -> Result<(), hound::Error> {
+        if self.playlist.len() >= MAX_PLAYLIST {
+            return Err(hound::Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "playlist is full",
+            )));
+        }
+        let path = path.to_string_lossy().into_owned();
+        let audio_file = Shared::new(&self.collector, AudioFile::open(&path)?);
+        self.playlist
+            .push((path.clone(), Shared::clone(&audio_file)));
+        if self.file.is_none() {
+            self.path = Some(path);
+            self.set_shared_file(audio_file);
+        } else {
+            self.send_msg(Message::Enqueue(audio_file));
+        }
+        Ok(())
+    }
+    /// skip to the start of the next file in the playlist
+    pub fn next(&mut self) {
+        self.send_msg(Message::Next);
+    }
+    /// skip to the start of the previous file in the playlist, or the start of the first
+    pub fn previous(&mut self) {
+        self.send_msg(Message::Previous);
+    }
+    /// the paths of the files in the playlist
+    pub fn playlist(&self) -> impl Iterator<Item = &str> {
+        self.playlist.iter().map(|(path, _)| path.as_str())
+    }
+    /// follow the player to the playlist item it is playing, returns true if the loaded file
+    /// changed
+    pub fn sync_playlist(&mut self) -> bool {
+        let index = self.transport.playlist_index();
+        if index == self.current {
+            return false;
+        }
+        let (path, audio_file) = if let Some(item) = self.playlist.get(index) {
+            item.clone()
+        } else {
+            return false;
+        };
+        self.current = index;
+        self.history.clear();
+        self.path = Some(path);
+        self.adopt_file(audio_file);
+        true
+    }
+    fn set_path(&mut self, path: &str) {
+        self.path = Some(path.to_owned());
+        if let Some(item) = self.playlist.get_mut(self.current) {
+            item.0 = path.to_owned();
+        }
+    }
+    /// replace the file being played
+    fn set_file(&mut self, audio_file: AudioFile) {
+        let audio_file = Shared::new(&self.collector, audio_file);
+        self.set_shared_file(audio_file);
+    }
+    fn set_shared_file(&mut self, audio_file: Shared<AudioFile>) {
+        if let Some(item) = self.playlist.get_mut(self.current) {
+            item.1 = Shared::clone(&audio_file);
+        }
+        self.adopt_file(Shared::clone(&audio_file));
+        self.send_msg(Message::NewFile(self.current, audio_file));
+    }
+    /// make a file the loaded file, without sending it to the player
+    fn adopt_file(&mut self, audio_file: Shared<AudioFile>) {
         self.num_samples = Some(audio_file.num_samples);
         self.num_channels = Some(audio_file.num_channels);
         self.sample_rate = Some(audio_file.sample_rate);
-        self.file = Some(Shared::clone(&audio_file));
-        self.send_msg(Message::NewFile(audio_file));
+        self.file = Some(audio_file);
+        self.loudness = None;
+        if self.comparison.is_some() {
+            self.update_comparison();
+        }
+    }
+    /// compare the loaded file against another file, which plays at the same position in
+    /// place of the loaded file while listening to B
+    pub fn compare_with(&mut self, path: &str) -> Result<(), hound::Error> {
+        let audio_file = Shared::new(&self.collector, AudioFile::open(path)?);
+        let loudness = integrated_loudness(&audio_file);
+        self.comparison = Some((Shared::clone(&audio_file), loudness));
+        self.send_msg(Message::Compare(Some(audio_file)));
+        self.update_comparison();
+        Ok(())
+    }
+    /// release the comparison file and go back to listening to the loaded file
+    pub fn stop_comparing(&mut self) {
+        self.comparison = None;
+        self.listening_to_b = false;
+        self.send_msg(Message::Compare(None));
+        self.update_comparison();
+    }
+    pub fn is_comparing(&self) -> bool {
+        self.comparison.is_some()
+    }
+    /// switch between hearing the loaded file (A) and the comparison file (B)
+    pub fn set_listening_to_b(&mut self, listening_to_b: bool) {
+        self.listening_to_b = listening_to_b && self.comparison.is_some();
+        self.update_comparison();
+    }
+    pub fn listening_to_b(&self) -> bool {
+        self.listening_to_b
+    }
+    /// choose how the channels of every file are routed to the outputs
+    pub fn set_channel_map(&mut self, map: ChannelMap) {
+        let map = Shared::new(&self.collector, map);
+        self.send_msg(Message::SetChannelMap(map));
+    }
+    /// mix the input into the output, on top of playback
+    pub fn monitor_input(&mut self, monitor: InputMonitor) {
+        let monitor = Owned::new(&self.collector, monitor);
+        self.send_msg(Message::SetInput(Some(monitor)));
     }
-    pub fn get_magnitude(&self, sample_idx: usize) -> f32 {
-        if let Some(file) = &self.file {
-            let ldx = sample_idx;
-            let rdx = sample_idx + file.num_samples;
-            (file.data[ldx].abs() + file.data[rdx].abs()) / 2.0
+    pub fn stop_monitoring(&mut self) {
+        self.send_msg(Message::SetInput(None));
+    }
+    /// set the gain of the monitored input, in decibels
+    pub fn set_monitor_gain(&mut self, db: f32) {
+        self.send_msg(Message::SetMonitorGain(db));
+    }
+    /// enable or disable the guard that silences output channels containing NaN or infinite
+    /// samples, which is enabled by default
+    pub fn set_guard(&mut self, guard: bool) {
+        self.send_msg(Message::SetGuard(guard));
+    }
+    /// follow a gain curve while playing, given as breakpoints of position in the file in
+    /// seconds and gain in decibels, so that fades and ducking can be heard before they are
+    /// applied. An empty curve clears it.
+    pub fn set_automation(&mut self, points: &[(f64, f32)]) {
+        let automation = if points.is_empty() {
+            None
         } else {
-            0.0
+            Some(Shared::new(&self.collector, Automation::new(points)))
+        };
+        self.send_msg(Message::SetAutomation(automation));
+    }
+    /// choose what happens when the playhead reaches the end of the file
+    pub fn set_end_of_file(&mut self, end_of_file: EndOfFile) {
+        self.send_msg(Message::SetEndOfFile(end_of_file));
+    }
+    /// match the loudness of the compared files, or hear their raw levels
+    pub fn set_match_loudness(&mut self, match_loudness: bool) {
+        self.match_loudness = match_loudness;
+        self.update_comparison();
+    }
+    /// the integrated loudness of the loaded file and the comparison file, in LUFS
+    pub fn loudness(&mut self) -> (Option<f64>, Option<f64>) {
+        if self.loudness.is_none() {
+            self.loudness = Some(
+                self.file
+                    .as_ref()
+                    .and_then(|file| integrated_loudness(file)),
+            );
+        }
+        let b = self.comparison.as_ref().and_then(|(_, loudness)| *loudness);
+        (self.loudness.flatten(), b)
+    }
+    /// send the gains of the compared files to the player
+    fn update_comparison(&mut self) {
+        let (a, b) = if self.match_loudness && self.comparison.is_some() {
+            self.loudness()
+        } else {
+            (None, None)
+        };
+        let (gain_a, gain_b) = match (a, b) {
+            (Some(a), Some(b)) => {
+                let quieter = a.min(b);
+                (
+                    db_to_gain((quieter - a) as f32),
+                    db_to_gain((quieter - b) as f32),
+                )
+            }
+            _ => (1.0, 1.0),
+        };
+        if self.listening_to_b {
+            self.send_msg(Message::SetSourceGains(0.0, gain_b));
+        } else {
+            self.send_msg(Message::SetSourceGains(gain_a, 0.0));
+        }
+    }
+    /// apply a destructive edit to a copy of the loaded file and start playing the copy,
+    /// keeping the previous version so the edit can be undone
+    pub fn edit(&mut self, edit: impl FnOnce(&mut AudioFile)) {
+        let previous = if let Some(file) = self.file.take() {
+            file
+        } else {
+            return;
+        };
+        let mut edited = AudioFile::clone(&previous);
+        edit(&mut edited);
+        self.history.push(previous);
+        self.set_file(edited);
+    }
+    /// revert the last edit, returns false if there was nothing to undo
+    pub fn undo(&mut self) -> bool {
+        if !self.history.can_undo() {
+            return false;
+        }
+        if let Some(current) = self.file.take() {
+            let previous = self.history.undo(current);
+            self.set_shared_file(previous);
+            true
+        } else {
+            false
+        }
+    }
+    /// reapply the last undone edit, returns false if there was nothing to redo
+    pub fn redo(&mut self) -> bool {
+        if !self.history.can_redo() {
+            return false;
+        }
+        if let Some(current) = self.file.take() {
+            let next = self.history.redo(current);
+            self.set_shared_file(next);
+            true
+        } else {
+            false
+        }
+    }
+    /// the approximate memory held by the loaded files and their history
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut files: Vec<&AudioFile> = vec![];
+        let loaded = self.file.iter().map(|file| &**file);
+        for file in self.playlist.iter().map(|(_, file)| &**file).chain(loaded) {
+            if !files.iter().any(|counted| std::ptr::eq(*counted, file)) {
+                files.push(file);
+            }
+        }
+        MemoryUsage {
+            files: files.iter().map(|file| file.memory_usage()).sum(),
+            history: self.history.memory_usage(),
+            comparison: self
+                .comparison
+                .as_ref()
+                .map_or(0, |(file, _)| file.memory_usage()),
+        }
+    }
+    /// limit the memory held by previous versions of the file, in bytes
+    pub fn set_undo_memory_limit(&mut self, bytes: usize) {
+        self.history.set_memory_limit(bytes);
+    }
+    /// write the loaded file back to where it was opened from
+    pub fn save(&self) -> Result<(), hound::Error> {
+        match (self.file.as_ref(), self.path.as_ref()) {
+            (Some(file), Some(path)) => file.save(path),
+            _ => Err(no_file_loaded()),
+        }
+    }
+    /// write the loaded file to a new path, which subsequent saves will use
+    pub fn save_as(&mut self, path: &str) -> Result<(), hound::Error> {
+        let file = self.file.as_ref().ok_or_else(no_file_loaded)?;
+        file.save(path)?;
+        self.set_path(path);
+        Ok(())
+    }
+}
+
+fn no_file_loaded() -> hound::Error {
+    hound::Error::IoError(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "no file loaded",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_stream::offline_stream;
+
+    const BLOCK_SIZE: usize = 64;
+
+    /// a file holding `value` in every sample
+    fn constant_file(num_channels: usize, num_samples: usize, value: f32) -> AudioFile {
+        AudioFile {
+            data: vec![value; num_channels * num_samples],
+            sample_rate: 48000.0,
+            num_channels,
+            num_samples,
+        }
+    }
+
+    /// play a file from the start on a stereo stream without fades, returning the first
+    /// `frames` frames of the interleaved output and the number of times the end was reached
+    fn render(file: AudioFile, frames: usize) -> (Vec<f32>, usize) {
+        let gc = Collector::new();
+        let (mut player, mut controller) = sample_player(&gc);
+        controller.set_fade_time(0.0);
+        controller.set_file(file);
+        controller.play();
+        let mut stream = offline_stream(48000.0, BLOCK_SIZE, 2, |mut context| {
+            player.advance(&mut context);
+        });
+        let output = stream.render(frames);
+        let mut reached_end = 0;
+        while let Some(event) = controller.poll_event() {
+            if event == PlayerEvent::ReachedEnd {
+                reached_end += 1;
+            }
+        }
+        (output, reached_end)
+    }
+
+    #[test]
+    fn last_partial_block_is_zero_filled() {
+        // the file ends 8 frames into its fourth block, and the stream renders on past it
+        let (output, reached_end) = render(constant_file(2, 200, 0.5), 6 * BLOCK_SIZE);
+        assert_eq!(output.len(), 2 * 6 * BLOCK_SIZE);
+        assert!(output[..2 * 200].iter().all(|&s| s == 0.5));
+        assert!(output[2 * 200..].iter().all(|&s| s == 0.0));
+        assert_eq!(reached_end, 1);
+    }
+
+    #[test]
+    fn empty_file_ends_immediately() {
+        let (output, reached_end) = render(constant_file(2, 0, 0.5), 2 * BLOCK_SIZE);
+        assert!(output.iter().all(|&s| s == 0.0));
+        assert_eq!(reached_end, 1);
+    }
+
+    #[test]
+    fn newer_seek_stays_after_a_nudge() {
+        let gc = Collector::new();
+        let (_player, mut controller) = sample_player(&gc);
+        while controller.flush() == 0 {
+            controller.play();
+        }
+        controller.seek(1.0);
+        controller.nudge(5);
+        controller.seek(2.0);
+        let waiting = controller.flush();
+        controller.seek(3.0);
+        // replacing the seek at the back doesn't grow the backlog
+        assert_eq!(controller.flush(), waiting);
+        let tail = controller
+            .backlog
+            .iter()
+            .rev()
+            .take(2)
+            .map(|message| match message {
+                Message::Seek(seconds) => Some(*seconds),
+                Message::Nudge(_) => None,
+                _ => panic!("unexpected message"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tail, vec![Some(3.0), None]);
+        let seeks = controller
+            .backlog
+            .iter()
+            .filter(|message| matches!(message, Message::Seek(_)))
+            .count();
+        assert_eq!(seeks, 1);
+    }
+
+    #[test]
+    fn full_backlog_keeps_the_newest_seek() {
+        let gc = Collector::new();
+        let (_player, mut controller) = sample_player(&gc);
+        // nothing drains the ring buffer, so once it is full every message waits in the backlog
+        while controller.flush() == 0 {
+            controller.play();
+        }
+        controller.seek(1.0);
+        while controller.flush() < MAX_BACKLOG {
+            controller.play();
+        }
+        controller.seek(2.0);
+        assert_eq!(controller.flush(), MAX_BACKLOG);
+        assert_eq!(controller.dropped_messages(), 0);
+        let seeks = controller
+            .backlog
+            .iter()
+            .filter_map(|message| match message {
+                Message::Seek(seconds) => Some(*seconds),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(seeks, vec![2.0]);
+        controller.play();
+        assert_eq!(controller.flush(), MAX_BACKLOG);
+        assert_eq!(controller.dropped_messages(), 1);
+    }
+
+    #[test]
+    fn mono_file_plays_on_both_channels() {
+        let (output, reached_end) = render(constant_file(1, 100, 0.25), 2 * BLOCK_SIZE);
+        for frame in output.chunks(2).take(100) {
+            assert_eq!(frame, &[0.25, 0.25]);
         }
+        assert!(output[2 * 100..].iter().all(|&s| s == 0.0));
+        assert_eq!(reached_end, 1);
     }
 }
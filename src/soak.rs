@@ -0,0 +1,283 @@
+use crate::audio_file::AudioFile;
+use crate::audio_stream::PlaybackContext;
+use crate::envelope::Envelope;
+use crate::sample_player::{sample_player, ChannelMap, EndOfFile, SamplePlayerController, VoiceId};
+use basedrop::{Collector, Shared};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Counts allocations made while the player is rendering, which must be zero
+struct CountingAllocator;
+
+thread_local! {
+    static RENDERING: Cell<bool> = const { Cell::new(false) };
+}
+
+static RENDER_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_allocation() {
+    if RENDERING
+        .try_with(|rendering| rendering.get())
+        .unwrap_or(false)
+    {
+        RENDER_ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_allocation();
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// A xorshift generator, so that a failing run can be repeated from its seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// a number in `0..n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % (n.max(1) as u64)) as usize
+    }
+
+    /// a number in `[0, 1)`
+    fn unit(&mut self) -> f64 {
+        ((self.next() >> 11) as f64) / ((1u64 << 53) as f64)
+    }
+}
+
+/// The state the soak test keeps between blocks
+struct Soak<'a> {
+    rng: Rng,
+    paths: &'a [String],
+    sample: Shared<AudioFile>,
+    voices: Vec<VoiceId>,
+    stream_time: u64,
+}
+
+impl<'a> Soak<'a> {
+    fn path(&mut self) -> &'a str {
+        let paths = self.paths;
+        &paths[self.rng.below(paths.len())]
+    }
+
+    /// make one random change through the controller, like the UI or MIDI input would
+    fn mutate(&mut self, controller: &mut SamplePlayerController) {
+        let num_samples = controller.duration_samples().unwrap_or(0);
+        let sample_rate = controller.sample_rate().unwrap_or(48000.0);
+        match self.rng.below(29) {
+            0 => controller.seek(self.rng.unit() * 1.2 * (num_samples as f64) / sample_rate),
+            1 => controller.seek_samples(self.rng.below(2 * num_samples + 1)),
+            2 => controller.nudge(self.rng.below(20000) as i64 - 10000),
+            3 | 4 => controller.play(),
+            5 => controller.stop(),
+            6 => controller.play_at(self.stream_time + self.rng.below(8192) as u64),
+            7 => {
+                let path = self.path();
+                controller.open(path).expect("failed to open a file");
+            }
+            8 => {
+                let path = self.path();
+                // the playlist filling up is expected
+                let _ = controller.enqueue(path);
+            }
+            9 => controller.next(),
+            10 => controller.previous(),
+            11 if self.rng.below(10) == 0 => controller.unload(),
+            12 | 13 => {
+                let start = self.rng.below(self.sample.num_samples + 1);
+                let db = -(self.rng.unit() * 24.0) as f32;
+                let looping = self.rng.below(4) == 0;
+                let id = controller.trigger_from(&self.sample, start, db, looping);
+                if self.voices.len() >= 64 {
+                    self.voices.remove(0);
+                }
+                self.voices.push(id);
+            }
+            14 if !self.voices.is_empty() => {
+                let id = self.voices[self.rng.below(self.voices.len())];
+                controller.stop_voice(id);
+            }
+            15 => controller.stop_voices(),
+            16 => controller.set_gain(self.rng.below(40), -(self.rng.unit() * 60.0) as f32),
+            17 => controller.set_master_gain((self.rng.unit() * 72.0 - 60.0) as f32),
+            18 => controller.set_fade_time(self.rng.unit() * 0.05),
+            19 => {
+                let all = EndOfFile::ALL;
+                controller.set_end_of_file(all[self.rng.below(all.len())]);
+            }
+            20 => {
+                let map = if self.rng.below(2) == 0 {
+                    ChannelMap::Auto
+                } else {
+                    let outputs = self.rng.below(9);
+                    let inputs = self.rng.below(9);
+                    let gains = (0..outputs)
+                        .map(|_| (0..inputs).map(|_| self.rng.unit() as f32).collect())
+                        .collect();
+                    ChannelMap::Matrix(gains)
+                };
+                controller.set_channel_map(map);
+            }
+            21 => {
+                if controller.is_comparing() && self.rng.below(2) == 0 {
+                    controller.stop_comparing();
+                } else {
+                    let path = self.path();
+                    controller
+                        .compare_with(path)
+                        .expect("failed to open a file");
+                }
+            }
+            22 => {
+                let b = !controller.listening_to_b();
+                controller.set_listening_to_b(b);
+            }
+            23 if self.rng.below(20) == 0 => {
+                let end = self.rng.below(num_samples + 1);
+                controller.edit(|file| file.apply_gain(0, end, -1.0));
+            }
+            24 => {
+                if self.rng.below(2) == 0 {
+                    controller.undo();
+                } else {
+                    controller.redo();
+                }
+            }
+            25 => controller.set_envelope(Envelope {
+                attack: self.rng.unit() * 0.1,
+                decay: self.rng.unit() * 0.1,
+                sustain: self.rng.unit() as f32,
+                release: self.rng.unit() * 0.2,
+            }),
+            26 => controller.step(self.rng.below(2000) as i64 - 1000),
+            27 => controller.step_blocks(self.rng.below(9) as i64 - 4),
+            28 => {
+                let length = (num_samples as f64) / sample_rate;
+                let points = (0..self.rng.below(8))
+                    .map(|_| (self.rng.unit() * length, -(self.rng.unit() * 40.0) as f32))
+                    .collect::<Vec<_>>();
+                controller.set_automation(&points);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Drive the player without a device for a number of seconds, making random changes between
+/// blocks of random sizes and checking after every block that the player didn't allocate,
+/// produced finite output, and kept its playhead inside the file. Panics on the first
+/// failure, reporting the seed to repeat the run with `PLAY_SOAK_SEED`.
+pub fn run(args: &[String]) {
+    let seconds = args.first().and_then(|arg| arg.parse::<f64>().ok());
+    let (seconds, paths) = match (seconds, args.get(1..)) {
+        (Some(seconds), Some(paths)) if !paths.is_empty() => (seconds, paths),
+        _ => {
+            println!("usage is: `play --soak <seconds> <path>...`");
+            std::process::exit(1);
+        }
+    };
+    let seed = std::env::var("PLAY_SOAK_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(1, |time| time.as_nanos() as u64)
+        })
+        .max(1);
+    println!("soaking for {}s with seed {}", seconds, seed);
+
+    let mut gc = Collector::new();
+    let (mut player, mut controller) = sample_player(&gc);
+    controller.load_file(&paths[0]);
+    let sample = controller
+        .load_sample(&paths[0])
+        .expect("failed to open a file");
+    let mut soak = Soak {
+        rng: Rng(seed),
+        paths,
+        sample,
+        voices: vec![],
+        stream_time: 0,
+    };
+
+    let mut buffer = vec![0.0f32; 1 << 16];
+    let deadline = Instant::now() + Duration::from_secs_f64(seconds);
+    let mut blocks = 0u64;
+    while Instant::now() < deadline {
+        for _ in 0..soak.rng.below(4) {
+            soak.mutate(&mut controller);
+        }
+        controller.flush();
+
+        let buffer_size = 1 + soak.rng.below(2048);
+        let num_channels = 1 + soak.rng.below(8);
+        let output = &mut buffer[..buffer_size * num_channels];
+        for sample in output.iter_mut() {
+            *sample = 0.0;
+        }
+        let mut context = PlaybackContext::new(buffer_size, 48000.0, num_channels, output);
+        context.stream_time = soak.stream_time;
+        context.xrun = soak.rng.below(100) == 0;
+
+        let before = RENDER_ALLOCATIONS.load(Ordering::SeqCst);
+        RENDERING.with(|rendering| rendering.set(true));
+        player.advance(&mut context);
+        RENDERING.with(|rendering| rendering.set(false));
+        let allocations = RENDER_ALLOCATIONS.load(Ordering::SeqCst) - before;
+
+        assert_eq!(
+            allocations, 0,
+            "block {} allocated {} times, seed {}",
+            blocks, allocations, seed
+        );
+        assert!(
+            context
+                .channels()
+                .flatten()
+                .all(|sample| sample.is_finite()),
+            "block {} produced samples that aren't finite, seed {}",
+            blocks,
+            seed
+        );
+        let transport = controller.transport();
+        assert!(
+            transport.playhead() <= transport.num_samples(),
+            "block {} left the playhead past the end of the file, seed {}",
+            blocks,
+            seed
+        );
+
+        soak.stream_time += buffer_size as u64;
+        while controller.poll_event().is_some() {}
+        controller.sync_playlist();
+        gc.collect();
+        blocks += 1;
+    }
+    println!("{} blocks without a failure", blocks);
+}
@@ -0,0 +1,21 @@
+/// Enter a span named `$name` lasting until the end of the enclosing block. Spans are only
+/// recorded with the `trace` feature, without it this expands to nothing.
+macro_rules! span {
+    ($name:expr) => {
+        #[cfg(feature = "trace")]
+        let _span = tracing::info_span!($name).entered();
+    };
+}
+
+/// Record spans as a chrome trace, which can be opened in `chrome://tracing` or Perfetto. The
+/// trace is written to `$PLAY_TRACE`, or `trace.json` if that isn't set, once the returned
+/// guard is dropped. Recording allocates, so traces of the audio thread show its timing but
+/// not its real time safety.
+#[cfg(feature = "trace")]
+pub fn start() -> tracing_chrome::FlushGuard {
+    use tracing_subscriber::prelude::*;
+    let path = std::env::var("PLAY_TRACE").unwrap_or_else(|_| "trace.json".to_string());
+    let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new().file(path).build();
+    tracing_subscriber::registry().with(layer).init();
+    guard
+}
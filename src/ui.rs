@@ -1,10 +1,24 @@
-use crate::sample_player::SamplePlayerController;
+use crate::audio_file::{AudioFile, Peaks};
+use crate::audio_stream::{input_stream, CueStream, StreamHandle};
+use crate::keymap::{Action, KeyBinding, KeyMap};
+use crate::latency::{self, Latency};
+use crate::layout::ChannelLayout;
+use crate::meter::{ChannelLevel, LevelMeter};
+use crate::midi::{self, NoteMap};
+use crate::sample_player::{EndOfFile, PlayerEvent, SamplePlayerController, Transport};
 use basedrop::Collector;
-use druid::kurbo::{Affine, BezPath};
-use druid::widget::{Button, Flex, Slider};
-use druid::{AppLauncher, PlatformError, RenderContext, Widget, WidgetExt, WindowDesc};
+use cpal::Stream;
+use druid::commands::{OPEN_FILE, REDO, SAVE_FILE_AS, SHOW_OPEN_PANEL, SHOW_SAVE_PANEL, UNDO};
+use druid::kurbo::{Affine, BezPath, Circle};
+use druid::piet::{FontFamily, Text, TextLayoutBuilder};
+use druid::widget::{Button, CrossAxisAlignment, Flex, Label, Painter, Scroll, Slider};
+use druid::{
+    AppDelegate, AppLauncher, Color, Command, Data, DelegateCtx, Env, Event, FileDialogOptions,
+    FileSpec, Handled, Lens, LensExt, LocalizedString, MenuDesc, MenuItem, PlatformError, Point,
+    Rect, RenderContext, Selector, Target, Widget, WidgetExt, WindowDesc, WindowId,
+};
 use std::marker;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, TryLockError};
 
 struct PlayheadController;
 impl<W: Widget<UiData>> druid::widget::Controller<UiData, W> for PlayheadController {
@@ -17,11 +31,7 @@ impl<W: Widget<UiData>> druid::widget::Controller<UiData, W> for PlayheadControl
         env: &druid::Env,
     ) {
         if let druid::Event::AnimFrame(_) = event {
-            if let Ok(controller) = data.controller.try_lock() {
-                if let Some(file) = controller.file.as_ref() {
-                    data.play_pos = (controller.playhead() as f64) / (file.num_samples as f64);
-                }
-            }
+            data.sync();
         }
         child.event(ctx, event, data, env);
     }
@@ -35,10 +45,10 @@ impl<W: Widget<UiData>> druid::widget::Controller<UiData, W> for PlayheadControl
         env: &druid::Env,
     ) {
         if let Ok(mut controller) = data.controller.try_lock() {
-            if let Some(file) = controller.file.as_ref() {
+            if let Some(num_samples) = controller.duration_samples() {
                 if ctx.is_active() {
-                    let playhead = (file.num_samples as f64) / (file.sample_rate) * data.play_pos;
-                    controller.seek(playhead);
+                    let playhead = (num_samples as f64) * data.play_pos;
+                    controller.seek_samples(playhead as usize);
                 }
             }
         }
@@ -46,27 +56,580 @@ impl<W: Widget<UiData>> druid::widget::Controller<UiData, W> for PlayheadControl
     }
 }
 
+/// the gain a slider controls
+#[derive(Clone, Copy)]
+enum GainTarget {
+    Master,
+    Channel(usize),
+    /// the monitored input
+    Monitor,
+    /// the output volume, applied by the stream
+    Volume,
+}
+
+/// forwards gain slider changes to the sample player, in decibels
+struct GainController<L> {
+    target: GainTarget,
+    lens: L,
+}
+
+impl<L: Lens<UiData, f64>, W: Widget<UiData>> druid::widget::Controller<UiData, W>
+    for GainController<L>
+{
+    fn update(
+        &mut self,
+        child: &mut W,
+        ctx: &mut druid::UpdateCtx<'_, '_>,
+        old_data: &UiData,
+        data: &UiData,
+        env: &druid::Env,
+    ) {
+        let db = self.lens.get(data);
+        if db != self.lens.get(old_data) {
+            if let GainTarget::Volume = self.target {
+                data.stream.controls().set_volume(db as f32);
+            } else if let Ok(mut controller) = data.controller.try_lock() {
+                match self.target {
+                    GainTarget::Master => controller.set_master_gain(db as f32),
+                    GainTarget::Channel(channel) => controller.set_gain(channel, db as f32),
+                    GainTarget::Monitor => controller.set_monitor_gain(db as f32),
+                    GainTarget::Volume => (),
+                }
+            }
+        }
+        child.update(ctx, old_data, data, env);
+    }
+}
+
+/// the result of a latency measurement, sent from the thread that measured it
+const LATENCY_MEASURED: Selector<Result<Latency, String>> = Selector::new("play.latency-measured");
+
+/// start or stop monitoring the input, handled by the delegate which owns the input stream
+const MONITOR_INPUT: Selector<bool> = Selector::new("play.monitor-input");
+/// start or stop playing on the cue output as well
+const CUE: Selector<bool> = Selector::new("play.cue");
+
+/// what the next open dialog is opening
+#[derive(druid::Data, Clone, Copy, PartialEq)]
+enum OpenTarget {
+    /// a file to trigger over the loaded file
+    Layer,
+    /// a file to add to the playlist
+    Queue,
+    /// a file to compare against the loaded file
+    Compare,
+    /// a file to replace the loaded file and the playlist
+    File,
+}
+
+/// what the next "save as" dialog is saving
+#[derive(druid::Data, Clone, Copy, PartialEq)]
+enum SaveTarget {
+    Clipboard,
+    File,
+}
+
 #[derive(druid::Data, druid::Lens, Clone)]
 pub struct UiData {
     controller: Arc<Mutex<SamplePlayerController>>,
+    /// playback state published by the audio thread, read without locking the controller
+    transport: Arc<Transport>,
+    /// the running stream, with how its callback has kept up and the last stage of its output
+    #[data(ignore)]
+    stream: StreamHandle,
+    /// true while the stream is closed to release the device
+    suspended: bool,
+    cueing: bool,
+    volume: f64,
+    muted: bool,
+    limiter: bool,
     is_playing: bool,
     play_pos: f64,
+    master_gain: f64,
+    left_gain: f64,
+    right_gain: f64,
+    /// the selected range of samples, if any
+    selection: Option<(usize, usize)>,
+    /// audio copied from a selection
+    clipboard: Option<Arc<AudioFile>>,
+    save_target: SaveTarget,
+    open_target: OpenTarget,
+    /// the playlist item the waveform was drawn from
+    playlist_index: usize,
+    /// a comparison file is loaded for A/B listening
+    comparing: bool,
+    listening_to_b: bool,
+    /// compared files are heard at matched loudness rather than their raw levels
+    match_loudness: bool,
+    end_of_file: EndOfFile,
+    /// the latency of the devices, measured by the calibration tool
+    latency: Option<Latency>,
+    /// the number of control messages the player fell too far behind to receive
+    dropped_messages: usize,
+    /// the input is mixed into the output
+    monitoring: bool,
+    monitor_gain: f64,
+    view: View,
+    keymap: Arc<KeyMap>,
+    /// the action waiting for a key press in the keymap editor, if any
+    rebinding: Option<Action>,
     peaks: Arc<Mutex<Vec<crate::audio_file::Peaks>>>,
+    /// output levels published by the audio thread
+    meter: Arc<Mutex<LevelMeter>>,
 }
 
-pub fn run(_gc: Collector, controller: SamplePlayerController) -> Result<(), PlatformError> {
-    let main_window = WindowDesc::new(move || ui_builder());
-    AppLauncher::with_window(main_window)
-        .use_simple_logger()
-        .launch(UiData {
-            peaks: Arc::new(Mutex::new(vec![
-                controller.file.as_ref().unwrap().spectral_peaks(0),
-                controller.file.as_ref().unwrap().spectral_peaks(1),
-            ])),
-            controller: Arc::new(Mutex::new(controller)),
+impl UiData {
+    /// the state of a window for a controller and the stream its player runs on
+    fn new(controller: Arc<Mutex<SamplePlayerController>>, stream: StreamHandle) -> Self {
+        let (peaks, transport, meter) = {
+            let controller = controller.lock().unwrap();
+            let peaks = controller
+                .file
+                .as_ref()
+                .map_or_else(Vec::new, |file| peaks(file));
+            (peaks, controller.transport(), controller.meter())
+        };
+        Self {
+            peaks: Arc::new(Mutex::new(peaks)),
+            transport,
+            meter,
+            volume: stream.controls().volume() as f64,
+            muted: stream.controls().muted(),
+            limiter: stream.controls().limiter(),
+            stream,
+            suspended: false,
+            cueing: false,
+            controller,
             is_playing: false,
             play_pos: 0.0,
+            master_gain: 0.0,
+            left_gain: 0.0,
+            right_gain: 0.0,
+            selection: None,
+            clipboard: None,
+            save_target: SaveTarget::Clipboard,
+            open_target: OpenTarget::Layer,
+            playlist_index: 0,
+            comparing: false,
+            listening_to_b: false,
+            match_loudness: true,
+            end_of_file: EndOfFile::Next,
+            latency: Latency::load_user(),
+            dropped_messages: 0,
+            monitoring: false,
+            monitor_gain: 0.0,
+            view: View::all(),
+            keymap: Arc::new(KeyMap::load_user()),
+            rebinding: None,
+        }
+    }
+
+    /// catch up with the player, once a frame
+    fn sync(&mut self) {
+        self.flush_messages();
+        self.handle_events();
+        self.follow_playlist();
+        self.play_pos = self.transport.position();
+    }
+
+    /// open a file chosen in the open panel, as whatever the panel was opened for
+    fn open(&mut self, path: &str) {
+        let open_target = self.open_target;
+        let result = self.with_controller(|controller| match open_target {
+            OpenTarget::Layer => controller.load_sample(path).map(|file| {
+                controller.trigger(&file, 0.0, false);
+            }),
+            OpenTarget::Queue => controller.enqueue(path),
+            OpenTarget::Compare => controller.compare_with(path),
+            OpenTarget::File => controller.open(path),
+        });
+        match (open_target, &result) {
+            (OpenTarget::Compare, _) => {
+                self.comparing = self.with_controller(|c| c.is_comparing()) == Some(true);
+            }
+            (OpenTarget::File, Some(Ok(()))) => {
+                self.playlist_index = 0;
+                self.selection = None;
+                self.view = View::all();
+                self.refresh_peaks();
+            }
+            _ => (),
+        }
+        if let Some(Err(e)) = result {
+            println!("failed to open {}: {}", path, e);
+        }
+    }
+
+    /// perform an action from a button or key binding, returning a command to submit if the
+    /// action needs one
+    fn perform(&mut self, action: Action) -> Option<Command> {
+        match action {
+            Action::PlayPause => self.toggle_play(),
+            Action::SeekStart => self.transport(|c| c.seek(0.0)),
+            Action::SkipForward => self.transport(|c| {
+                if let Some(sample_rate) = c.sample_rate() {
+                    c.nudge((sample_rate * 0.15) as i64);
+                } else {
+                    println!("no file loaded");
+                }
+            }),
+            Action::NudgeBack => self.transport(|c| c.nudge(-1)),
+            Action::NudgeForward => self.transport(|c| c.nudge(1)),
+            Action::NudgeBackFar => self.transport(|c| c.nudge(-1000)),
+            Action::NudgeForwardFar => self.transport(|c| c.nudge(1000)),
+            Action::StepBack => self.transport(|c| c.step_blocks(-1)),
+            Action::StepForward => self.transport(|c| c.step_blocks(1)),
+            Action::ZoomIn => self.zoom(0.5),
+            Action::ZoomOut => self.zoom(2.0),
+            Action::ZoomToFit => self.view = View::all(),
+            Action::Copy => self.copy_selection(),
+            Action::Trim => {
+                self.edit_selection(|file, start, end| *file = file.slice(start, end));
+                self.selection = None;
+            }
+            Action::FadeIn => self.edit_selection(|file, start, end| file.fade_in(start, end)),
+            Action::FadeOut => self.edit_selection(|file, start, end| file.fade_out(start, end)),
+            Action::GainUp => {
+                self.edit_selection(|file, start, end| file.apply_gain(start, end, 3.0))
+            }
+            Action::GainDown => {
+                self.edit_selection(|file, start, end| file.apply_gain(start, end, -3.0))
+            }
+            Action::Undo => self.undo(),
+            Action::Redo => self.redo(),
+            Action::Save => {
+                if let Some(Err(e)) = self.with_controller(|c| c.save()) {
+                    println!("failed to save: {}", e);
+                }
+            }
+            Action::SaveAs => {
+                self.save_target = SaveTarget::File;
+                return Some(SHOW_SAVE_PANEL.with(wav_dialog()));
+            }
+            Action::ToggleAB => {
+                let listening_to_b = !self.listening_to_b;
+                if let Some(b) = self.with_controller(|c| {
+                    c.set_listening_to_b(listening_to_b);
+                    c.listening_to_b()
+                }) {
+                    self.listening_to_b = b;
+                }
+            }
+        }
+        None
+    }
+
+    /// send a transport change to the controller
+    fn transport(&self, f: impl FnOnce(&mut SamplePlayerController)) {
+        self.with_controller(f);
+    }
+
+    /// run `f` with the controller locked. The MIDI thread only holds the lock while it sends
+    /// a note, so if it has the lock this waits for it rather than dropping the action.
+    fn with_controller<R>(&self, f: impl FnOnce(&mut SamplePlayerController) -> R) -> Option<R> {
+        let locked = match self.controller.try_lock() {
+            Ok(controller) => Ok(controller),
+            Err(TryLockError::WouldBlock) => self.controller.lock().map_err(|_| ()),
+            Err(TryLockError::Poisoned(_)) => Err(()),
+        };
+        if let Ok(mut controller) = locked {
+            Some(f(&mut controller))
+        } else {
+            println!("controller mutex was poisoned");
+            None
+        }
+    }
+
+    /// start or stop the transport, `is_playing` follows once the player reports the change
+    fn toggle_play(&mut self) {
+        let play = !self.transport.is_playing();
+        self.with_controller(|c| if play { c.play() } else { c.stop() });
+    }
+
+    /// zoom the waveform around its center
+    fn zoom(&mut self, factor: f64) {
+        if let Some(Some(num_samples)) = self.with_controller(|c| c.duration_samples()) {
+            self.view.zoom(factor, 0.5, num_samples);
+        }
+    }
+
+    fn copy_selection(&mut self) {
+        let (start, end) = if let Some(selection) = self.selection {
+            selection
+        } else {
+            println!("nothing selected");
+            return;
+        };
+        let clip = self
+            .with_controller(|c| c.file.as_ref().map(|file| file.slice(start, end)))
+            .flatten();
+        if let Some(clip) = clip {
+            self.clipboard = Some(Arc::new(clip));
+        }
+    }
+
+    /// apply a destructive edit to the selected range of the loaded file
+    fn edit_selection(&mut self, edit: impl FnOnce(&mut AudioFile, usize, usize)) {
+        let (start, end) = if let Some(selection) = self.selection {
+            selection
+        } else {
+            println!("nothing selected");
+            return;
+        };
+        if self
+            .with_controller(|c| c.edit(|file| edit(file, start, end)))
+            .is_some()
+        {
+            self.refresh_peaks();
+        }
+    }
+
+    fn undo(&mut self) {
+        if self.with_controller(|c| c.undo()) == Some(true) {
+            self.selection = None;
+            self.refresh_peaks();
+        }
+    }
+
+    fn redo(&mut self) {
+        if self.with_controller(|c| c.redo()) == Some(true) {
+            self.selection = None;
+            self.refresh_peaks();
+        }
+    }
+
+    /// the sample being heard, which lags the playhead by the output latency while playing.
+    /// The measured latency is used if there is one, otherwise the latency the device reports.
+    fn heard_playhead(&self, sample_rate: f64) -> usize {
+        let playhead = self.transport.playhead();
+        if !self.transport.is_playing() {
+            return playhead;
+        }
+        let latency = match self.latency {
+            Some(latency) => (latency.output() * sample_rate) as usize,
+            None => self.transport.output_latency(),
+        };
+        playhead.saturating_sub(latency)
+    }
+
+    /// update the UI from what happened on the audio thread
+    fn handle_events(&mut self) {
+        let mut controller = if let Ok(controller) = self.controller.try_lock() {
+            controller
+        } else {
+            return;
+        };
+        while let Some(event) = controller.poll_event() {
+            match event {
+                PlayerEvent::Started => self.is_playing = true,
+                PlayerEvent::Stopped | PlayerEvent::ReachedEnd => self.is_playing = false,
+                PlayerEvent::FileLoaded(_) => (),
+                PlayerEvent::Xrun => println!("audio dropout"),
+                PlayerEvent::DeviceChanged => println!("audio device changed"),
+                PlayerEvent::Error(e) => println!("player error: {}", e),
+                PlayerEvent::NonFinite(channel) => {
+                    println!("silenced invalid samples on channel {}", channel + 1)
+                }
+            }
+        }
+    }
+
+    /// send control messages that were waiting for the player to catch up
+    fn flush_messages(&mut self) {
+        if let Ok(mut controller) = self.controller.try_lock() {
+            controller.flush();
+            let dropped = controller.dropped_messages();
+            if dropped > self.dropped_messages {
+                println!(
+                    "{} control messages were dropped",
+                    dropped - self.dropped_messages
+                );
+                self.dropped_messages = dropped;
+            }
+        }
+    }
+
+    /// redraw the waveform when the player moves on to another file in the playlist
+    fn follow_playlist(&mut self) {
+        let index = self.transport.playlist_index();
+        if index == self.playlist_index {
+            return;
+        }
+        if let Some(changed) = self.with_controller(|controller| controller.sync_playlist()) {
+            self.playlist_index = index;
+            if changed {
+                self.selection = None;
+                self.view = View::all();
+                self.refresh_peaks();
+            }
+        }
+    }
+
+    /// recompute the waveform overview after the loaded file has changed
+    fn refresh_peaks(&mut self) {
+        let peaks =
+            self.with_controller(|c| c.file.as_ref().map_or_else(Vec::new, |file| peaks(file)));
+        if let Some(peaks) = peaks {
+            self.peaks = Arc::new(Mutex::new(peaks));
+        }
+    }
+}
+
+/// the waveform overview of every channel in a file
+fn peaks(file: &AudioFile) -> Vec<Peaks> {
+    (0..file.num_channels)
+        .map(|ch| file.spectral_peaks(ch))
+        .collect()
+}
+
+/// handles application level commands, like the results of file dialogs
+struct Delegate {
+    /// frees files released by the audio thread
+    collector: Collector,
+    /// the input stream, while it is being monitored
+    input: Option<Stream>,
+    /// the cue output, while it is open
+    cue: Option<CueStream>,
+}
+
+impl AppDelegate<UiData> for Delegate {
+    fn event(
+        &mut self,
+        ctx: &mut DelegateCtx,
+        window_id: WindowId,
+        event: Event,
+        data: &mut UiData,
+        _env: &Env,
+    ) -> Option<Event> {
+        span!("ui event");
+        self.collector.collect();
+        if let Event::KeyDown(key) = &event {
+            let binding = KeyBinding::from_event(key);
+            if binding.is_modifier() {
+                return Some(event);
+            }
+            if let Some(action) = data.rebinding.take() {
+                if !binding.is_escape() {
+                    let keymap = Arc::make_mut(&mut data.keymap);
+                    keymap.rebind(action, binding);
+                    if let Err(e) = keymap.save_user() {
+                        println!("failed to save keymap: {}", e);
+                    }
+                }
+                return None;
+            }
+            if let Some(action) = data.keymap.action(&binding) {
+                if let Some(cmd) = data.perform(action) {
+                    ctx.submit_command(cmd.to(window_id));
+                }
+                return None;
+            }
+        }
+        Some(event)
+    }
+
+    fn command(
+        &mut self,
+        _ctx: &mut DelegateCtx,
+        _target: Target,
+        cmd: &Command,
+        data: &mut UiData,
+        _env: &Env,
+    ) -> Handled {
+        span!("ui command");
+        self.collector.collect();
+        if cmd.is(UNDO) {
+            data.perform(Action::Undo);
+            return Handled::Yes;
+        }
+        if cmd.is(REDO) {
+            data.perform(Action::Redo);
+            return Handled::Yes;
+        }
+        if let Some(result) = cmd.get(LATENCY_MEASURED) {
+            match result {
+                Ok(latency) => {
+                    data.latency = Some(*latency);
+                    if let Err(e) = latency.save_user() {
+                        println!("failed to save latency: {}", e);
+                    }
+                }
+                Err(e) => println!("failed to measure latency: {}", e),
+            }
+            return Handled::Yes;
+        }
+        if let Some(cue) = cmd.get(CUE) {
+            if *cue {
+                match data.stream.open_cue(None) {
+                    Ok(cue) => {
+                        self.cue = Some(cue);
+                        data.cueing = true;
+                    }
+                    Err(e) => println!("failed to open the cue output: {}", e),
+                }
+            } else {
+                self.cue = None;
+                data.cueing = false;
+            }
+            return Handled::Yes;
+        }
+        if let Some(monitor) = cmd.get(MONITOR_INPUT) {
+            if *monitor {
+                match input_stream(data.stream.device().as_deref()) {
+                    Ok((stream, input)) => {
+                        data.with_controller(|c| c.monitor_input(input));
+                        self.input = Some(stream);
+                        data.monitoring = true;
+                    }
+                    Err(e) => println!("failed to monitor input: {}", e),
+                }
+            } else {
+                data.with_controller(|c| c.stop_monitoring());
+                self.input = None;
+                data.monitoring = false;
+            }
+            return Handled::Yes;
+        }
+        if let Some(file_info) = cmd.get(OPEN_FILE) {
+            data.open(&file_info.path().to_string_lossy());
+            return Handled::Yes;
+        }
+        if let Some(file_info) = cmd.get(SAVE_FILE_AS) {
+            let path = file_info.path();
+            let result = match data.save_target {
+                SaveTarget::Clipboard => data.clipboard.as_ref().map(|clip| clip.save(path)),
+                SaveTarget::File => {
+                    data.with_controller(|controller| controller.save_as(&path.to_string_lossy()))
+                }
+            };
+            if let Some(Err(e)) = result {
+                println!("failed to save: {}", e);
+            }
+            return Handled::Yes;
+        }
+        Handled::No
+    }
+}
+
+pub fn run(
+    gc: Collector,
+    controller: SamplePlayerController,
+    stream: StreamHandle,
+) -> Result<(), PlatformError> {
+    let main_window = WindowDesc::new(move || ui_builder()).menu(menu());
+    let controller = Arc::new(Mutex::new(controller));
+    // notes are played for as long as the connection is held
+    let _midi = midi::connect(Arc::clone(&controller), NoteMap::FromStart)
+        .map_err(|e| println!("MIDI input is disabled: {}", e))
+        .ok();
+    AppLauncher::with_window(main_window)
+        .delegate(Delegate {
+            collector: gc,
+            input: None,
+            cue: None,
         })
+        .use_simple_logger()
+        .launch(UiData::new(controller, stream))
 }
 
 struct Anim<D, W> {
@@ -128,50 +691,257 @@ where
 }
 
 fn ui_builder() -> impl Widget<UiData> {
-    let open = Button::new("Open").on_click(move |_ctx, _data: &mut UiData, _env| {
-        // todo: file dialog options
+    let open = Button::new("Open").on_click(move |ctx, data: &mut UiData, _env| {
+        data.open_target = OpenTarget::File;
+        ctx.submit_command(SHOW_OPEN_PANEL.with(wav_dialog()));
+    });
+    let close = Button::new("Close").on_click(|_ctx, data: &mut UiData, _| {
+        data.with_controller(|c| c.unload());
+        data.playlist_index = 0;
+        data.selection = None;
+        data.view = View::all();
+        data.refresh_peaks();
     });
 
-    let seek_right = Button::new(">>").on_click(|_, data: &mut UiData, _| {
-        let controller = data.controller.try_lock();
-        if controller.is_err() {
-            println!("mutex was poisoned");
-            return;
+    let seek_right = action_button(">>", Action::SkipForward);
+    let play = action_button("|>", Action::PlayPause).padding(5.0);
+    let seek_left = action_button("<<", Action::SeekStart);
+    let previous = Button::new("|<").on_click(|_ctx, data: &mut UiData, _| {
+        data.with_controller(|controller| controller.previous());
+    });
+    let next = Button::new(">|").on_click(|_ctx, data: &mut UiData, _| {
+        data.with_controller(|controller| controller.next());
+    });
+    let queue = Button::new("Queue...").on_click(|ctx, data: &mut UiData, _| {
+        data.open_target = OpenTarget::Queue;
+        ctx.submit_command(SHOW_OPEN_PANEL.with(wav_dialog()));
+    });
+    let copy = action_button("Copy", Action::Copy);
+
+    let save_clipboard =
+        Button::new("Save clipboard as...").on_click(|ctx, data: &mut UiData, _| {
+            if data.clipboard.is_none() {
+                println!("clipboard is empty");
+                return;
+            }
+            data.save_target = SaveTarget::Clipboard;
+            ctx.submit_command(SHOW_SAVE_PANEL.with(wav_dialog()));
+        });
+
+    let trim = action_button("Trim", Action::Trim);
+    let fade_in = action_button("Fade in", Action::FadeIn);
+    let fade_out = action_button("Fade out", Action::FadeOut);
+    let gain_down = action_button("-3 dB", Action::GainDown);
+    let gain_up = action_button("+3 dB", Action::GainUp);
+    let undo = action_button("Undo", Action::Undo);
+    let redo = action_button("Redo", Action::Redo);
+    let save = action_button("Save", Action::Save);
+    let save_as = action_button("Save as...", Action::SaveAs);
+
+    let layer = Button::new("Layer...").on_click(|ctx, data: &mut UiData, _| {
+        data.open_target = OpenTarget::Layer;
+        ctx.submit_command(SHOW_OPEN_PANEL.with(wav_dialog()));
+    });
+    let stop_layers = Button::new("Stop layers").on_click(|_ctx, data: &mut UiData, _| {
+        data.with_controller(|controller| controller.stop_voices());
+    });
+
+    let compare = Button::new("Compare...").on_click(|ctx, data: &mut UiData, _| {
+        data.open_target = OpenTarget::Compare;
+        ctx.submit_command(SHOW_OPEN_PANEL.with(wav_dialog()));
+    });
+    let toggle_ab = Button::from_label(Label::new(|data: &UiData, _: &Env| {
+        if data.listening_to_b {
+            "Listening to B".to_string()
+        } else {
+            "Listening to A".to_string()
         }
-        let mut controller = controller.unwrap();
-        let file = controller.file.as_ref();
-        if file.is_none() {
-            println!("no file loaded");
-            return;
+    }))
+    .on_click(|_ctx, data: &mut UiData, _| {
+        data.perform(Action::ToggleAB);
+    });
+    let match_loudness = Button::from_label(Label::new(|data: &UiData, _: &Env| {
+        if data.match_loudness {
+            "Matched loudness".to_string()
+        } else {
+            "Raw levels".to_string()
+        }
+    }))
+    .on_click(|_ctx, data: &mut UiData, _| {
+        data.match_loudness = !data.match_loudness;
+        let match_loudness = data.match_loudness;
+        data.with_controller(|c| c.set_match_loudness(match_loudness));
+    });
+    let stop_comparing = Button::new("Stop comparing").on_click(|_ctx, data: &mut UiData, _| {
+        data.with_controller(|c| c.stop_comparing());
+        data.comparing = false;
+        data.listening_to_b = false;
+    });
+    let loudness = Label::new(|data: &UiData, _: &Env| {
+        if !data.comparing {
+            return String::new();
+        }
+        let loudness = data
+            .controller
+            .try_lock()
+            .ok()
+            .map(|mut controller| controller.loudness());
+        match loudness {
+            Some((Some(a), Some(b))) => format!("A {:.1} LUFS, B {:.1} LUFS", a, b),
+            _ => "loudness unknown".to_string(),
         }
-        let file = file.unwrap();
-        let playhead = (controller.playhead() as f64) / file.sample_rate;
-        controller.seek(playhead + 0.15);
     });
 
-    let play = Button::new("|>")
-        .on_click(move |_ctx, data: &mut UiData, _env| {
-            data.is_playing = !data.is_playing;
-            if let Ok(mut controller) = data.controller.try_lock() {
-                if data.is_playing {
-                    controller.play();
-                } else {
-                    controller.stop();
-                }
-            } else {
-                println!("controller mutex was poisoned");
+    let end_of_file = Button::from_label(Label::new(|data: &UiData, _: &Env| {
+        data.end_of_file.description().to_string()
+    }))
+    .on_click(|_ctx, data: &mut UiData, _| {
+        let all = EndOfFile::ALL;
+        let index = all.iter().position(|e| *e == data.end_of_file).unwrap_or(0);
+        data.end_of_file = all[(index + 1) % all.len()];
+        let end_of_file = data.end_of_file;
+        data.with_controller(|c| c.set_end_of_file(end_of_file));
+    });
+
+    let calibrate = Button::new("Calibrate latency").on_click(|ctx, data: &mut UiData, _| {
+        let sink = ctx.get_external_handle();
+        let device = data.stream.device();
+        std::thread::spawn(move || {
+            let result = latency::measure(device.as_deref());
+            if sink
+                .submit_command(LATENCY_MEASURED, result, Target::Auto)
+                .is_err()
+            {
+                println!("failed to report latency measurement");
             }
-        })
-        .padding(5.0);
+        });
+    });
+    let latency_label = Label::new(|data: &UiData, _: &Env| match data.latency {
+        Some(latency) => format!(
+            "latency {:.1} ms round trip, {:.1} ms output",
+            latency.round_trip * 1000.0,
+            latency.output() * 1000.0
+        ),
+        None => "latency not measured".to_string(),
+    });
 
-    let seek_left = Button::new("<<").on_click(|_, data: &mut UiData, _| {
-        let controller = data.controller.try_lock();
-        if controller.is_err() {
-            println!("mutex was poisoned");
-            return;
+    let monitor = Button::from_label(Label::new(|data: &UiData, _: &Env| {
+        if data.monitoring {
+            "Stop monitoring".to_string()
+        } else {
+            "Monitor input".to_string()
+        }
+    }))
+    .on_click(|ctx, data: &mut UiData, _| {
+        ctx.submit_command(MONITOR_INPUT.with(!data.monitoring));
+    });
+
+    let suspend = Button::from_label(Label::new(|data: &UiData, _: &Env| {
+        if data.suspended {
+            "Resume audio".to_string()
+        } else {
+            "Suspend audio".to_string()
+        }
+    }))
+    .on_click(|_ctx, data: &mut UiData, _| {
+        let result = if data.suspended {
+            data.stream.resume()
+        } else {
+            data.stream.suspend()
+        };
+        match result {
+            Ok(()) => data.suspended = !data.suspended,
+            Err(e) => println!("failed to suspend or resume audio: {}", e),
+        }
+    });
+
+    let cue = Button::from_label(Label::new(|data: &UiData, _: &Env| {
+        if data.cueing {
+            "Stop cueing".to_string()
+        } else {
+            "Cue".to_string()
+        }
+    }))
+    .on_click(|ctx, data: &mut UiData, _| {
+        ctx.submit_command(CUE.with(!data.cueing));
+    });
+
+    let mute = Button::from_label(Label::new(|data: &UiData, _: &Env| {
+        if data.muted {
+            "Unmute".to_string()
+        } else {
+            "Mute".to_string()
+        }
+    }))
+    .on_click(|_ctx, data: &mut UiData, _| {
+        data.muted = !data.muted;
+        data.stream.controls().set_muted(data.muted);
+    });
+
+    let limiter = Button::from_label(Label::new(|data: &UiData, _: &Env| {
+        if !data.limiter {
+            "Limiter off".to_string()
+        } else {
+            format!("Limiting {:.1} dB", data.stream.controls().reduction())
+        }
+    }))
+    .on_click(|_ctx, data: &mut UiData, _| {
+        data.limiter = !data.limiter;
+        data.stream.controls().set_limiter(data.limiter);
+    });
+
+    let memory = Label::new(|data: &UiData, _: &Env| {
+        let usage = data
+            .controller
+            .try_lock()
+            .ok()
+            .map(|controller| controller.memory_usage());
+        let waveform = data
+            .peaks
+            .try_lock()
+            .ok()
+            .map_or(0, |peaks| peaks.iter().map(Peaks::memory_usage).sum());
+        let mb = |bytes: usize| (bytes as f64) / ((1 << 20) as f64);
+        match usage {
+            Some(usage) => format!(
+                "memory {:.1} MB files, {:.1} MB undo, {:.1} MB compare, {:.1} MB waveform",
+                mb(usage.files),
+                mb(usage.history),
+                mb(usage.comparison),
+                mb(waveform)
+            ),
+            None => String::new(),
+        }
+    });
+
+    let stats = Label::new(|data: &UiData, _: &Env| {
+        format!(
+            "{} xruns, {} overloads, worst callback {:.2} ms ({:.0}% of a block)",
+            data.stream.stats().xruns(),
+            data.stream.stats().overloads(),
+            data.stream.stats().worst_callback().as_secs_f64() * 1000.0,
+            data.stream.stats().worst_load() * 100.0
+        )
+    });
+
+    let keys = Button::new("Keys...").on_click(|ctx, _data: &mut UiData, _| {
+        ctx.new_window(
+            WindowDesc::new(keymap_editor)
+                .title("keyboard shortcuts")
+                .window_size((500.0, 600.0)),
+        );
+    });
+
+    let paste = Button::new("Paste into new window").on_click(|ctx, data: &mut UiData, _| {
+        if let Some(clipboard) = data.clipboard.clone() {
+            ctx.new_window(
+                WindowDesc::new(move || clipboard_view(clipboard))
+                    .title("clipboard")
+                    .window_size((600.0, 200.0)),
+            );
+        } else {
+            println!("clipboard is empty");
         }
-        let mut controller = controller.unwrap();
-        controller.seek(0.0);
     });
 
     // let waveform = Painter::new(move |ctx, data: &UiData, env|{
@@ -197,15 +967,55 @@ fn ui_builder() -> impl Widget<UiData> {
     //     cursor.apply_affine(Affine::scale_non_uniform(size.width, size.height));
     //     ctx.stroke(&cursor, &druid::Color::WHITE, 1.0);
     // })
-    let waveform = WaveformView {}.padding(5.0).fix_height(100.0);
+    let waveform = WaveformView::new().padding(5.0).fix_height(100.0);
 
     Flex::column()
         .with_child(
             Flex::row()
                 .with_child(open)
+                .with_child(close)
+                .with_child(previous)
                 .with_child(seek_left)
                 .with_child(play)
-                .with_child(seek_right),
+                .with_child(seek_right)
+                .with_child(next)
+                .with_child(queue)
+                .with_child(end_of_file)
+                .with_child(copy)
+                .with_child(save_clipboard)
+                .with_child(paste)
+                .with_child(layer)
+                .with_child(stop_layers)
+                .with_child(keys),
+        )
+        .with_child(
+            Flex::row()
+                .with_child(trim)
+                .with_child(fade_in)
+                .with_child(fade_out)
+                .with_child(gain_down)
+                .with_child(gain_up)
+                .with_child(undo)
+                .with_child(redo)
+                .with_child(save)
+                .with_child(save_as)
+                .with_child(memory)
+                .with_child(stats),
+        )
+        .with_child(
+            Flex::row()
+                .with_child(compare)
+                .with_child(toggle_ab)
+                .with_child(match_loudness)
+                .with_child(stop_comparing)
+                .with_child(loudness)
+                .with_child(calibrate)
+                .with_child(latency_label)
+                .with_child(monitor)
+                .with_child(mute)
+                .with_child(limiter)
+                .with_child(suspend)
+                .with_child(cue),
         )
         .with_child(Anim::new(
             Slider::new()
@@ -214,66 +1024,359 @@ fn ui_builder() -> impl Widget<UiData> {
                 .controller(PlayheadController),
         ))
         .with_child(Anim::new(waveform.padding(5.0)))
+        .with_child(
+            LevelMeterView::new()
+                .fix_height(24.0)
+                .expand_width()
+                .padding(5.0),
+        )
+        .with_child(gain_slider(
+            "master",
+            GainTarget::Master,
+            UiData::master_gain,
+        ))
+        .with_child(gain_slider("L", GainTarget::Channel(0), UiData::left_gain))
+        .with_child(gain_slider("R", GainTarget::Channel(1), UiData::right_gain))
+        .with_child(gain_slider(
+            "input",
+            GainTarget::Monitor,
+            UiData::monitor_gain,
+        ))
+        .with_child(gain_slider("volume", GainTarget::Volume, UiData::volume))
+}
+
+/// a button that performs an action
+fn action_button(label: &str, action: Action) -> impl Widget<UiData> {
+    Button::new(label).on_click(move |ctx, data: &mut UiData, _| {
+        if let Some(cmd) = data.perform(action) {
+            ctx.submit_command(cmd);
+        }
+    })
+}
+
+/// lists every action with its key bindings, and lets them be rebound
+fn keymap_editor() -> impl Widget<UiData> {
+    let mut actions = Flex::column().cross_axis_alignment(CrossAxisAlignment::Start);
+    for &action in Action::ALL {
+        let keys = Label::new(move |data: &UiData, _env: &Env| {
+            if data.rebinding == Some(action) {
+                "press a key, or escape to cancel".to_owned()
+            } else {
+                data.keymap.describe(action)
+            }
+        });
+        let rebind = Button::new("Rebind").on_click(move |_, data: &mut UiData, _| {
+            data.rebinding = Some(action);
+        });
+        actions.add_child(
+            Flex::row()
+                .with_child(Label::new(action.description()).fix_width(200.0))
+                .with_child(keys.fix_width(200.0))
+                .with_child(rebind)
+                .padding(2.0),
+        );
+    }
+    Scroll::new(actions.padding(10.0)).vertical()
+}
+
+/// the application menus
+fn menu() -> MenuDesc<UiData> {
+    let edit =
+        MenuDesc::new(LocalizedString::new("common-menu-edit-menu").with_placeholder("Edit"))
+            .append(MenuItem::new(
+                LocalizedString::new("common-menu-undo").with_placeholder("Undo"),
+                UNDO,
+            ))
+            .append(MenuItem::new(
+                LocalizedString::new("common-menu-redo").with_placeholder("Redo"),
+                REDO,
+            ));
+    MenuDesc::empty().append(edit)
+}
+
+/// file dialog options for .wav files
+fn wav_dialog() -> FileDialogOptions {
+    FileDialogOptions::new().allowed_types(vec![FileSpec::new("Wav", &["wav"])])
+}
+
+/// a static view of audio pasted from the clipboard
+fn clipboard_view(clip: Arc<AudioFile>) -> impl Widget<UiData> {
+    Painter::new(move |ctx, _data: &UiData, env| {
+        let size = ctx.size();
+        ctx.fill(size.to_rect(), &env.get(druid::theme::BACKGROUND_DARK));
+        if clip.num_samples == 0 {
+            return;
+        }
+        let lane_height = size.height / (clip.num_channels as f64);
+        let step_size = (((clip.num_samples as f64) / size.width) as usize).max(1);
+        for ch in 0..clip.num_channels {
+            let (mut peak, mut avg) = clip.plot(step_size, ch, 0, clip.num_samples);
+            let transform = Affine::translate((0.0, lane_height * ch as f64))
+                * Affine::scale_non_uniform(size.width, lane_height);
+            peak.apply_affine(transform);
+            avg.apply_affine(transform);
+            if step_size == 1 {
+                ctx.stroke(&peak, &Color::grey(0.8), 1.0);
+            } else {
+                ctx.fill(&peak, &Color::grey(0.5));
+                ctx.fill(&avg, &Color::grey(0.8));
+            }
+        }
+    })
+    .padding(5.0)
+}
+
+/// a labelled slider controlling a gain
+fn gain_slider<L>(label: &str, target: GainTarget, lens: L) -> impl Widget<UiData>
+where
+    L: Lens<UiData, f64> + Clone + 'static,
+{
+    Flex::row()
+        .with_child(Label::new(label).fix_width(50.0))
+        .with_flex_child(
+            Slider::new()
+                .with_range(-60.0, 12.0)
+                .lens(lens.clone())
+                .expand_width()
+                .controller(GainController { target, lens }),
+            1.0,
+        )
+        .padding(5.0)
+}
+
+/// The visible portion of the file, normalized to `[0, 1]`
+#[derive(druid::Data, Clone, Copy, PartialEq)]
+struct View {
+    start: f64,
+    end: f64,
+}
+
+impl View {
+    /// the whole file
+    fn all() -> Self {
+        Self {
+            start: 0.0,
+            end: 1.0,
+        }
+    }
+
+    /// scale the visible span by `factor` around `anchor`, a position within the view
+    /// normalized to `[0, 1]`
+    fn zoom(&mut self, factor: f64, anchor: f64, num_samples: usize) {
+        let span = self.end - self.start;
+        let min_span = (16.0 / (num_samples as f64)).min(1.0);
+        let new_span = (span * factor).max(min_span).min(1.0);
+        let center = self.start + anchor * span;
+        self.start = (center - anchor * new_span).max(0.0).min(1.0 - new_span);
+        self.end = self.start + new_span;
+    }
+
+    /// scroll the view by `delta`, a fraction of the visible span
+    fn pan(&mut self, delta: f64) {
+        let span = self.end - self.start;
+        self.start = (self.start + delta * span).max(0.0).min(1.0 - span);
+        self.end = self.start + span;
+    }
+
+    /// the range of samples that are visible
+    fn visible_samples(&self, num_samples: usize) -> (usize, usize) {
+        let len = num_samples as f64;
+        let start = ((self.start * len).floor() as usize).min(num_samples.saturating_sub(1));
+        let end = ((self.end * len).ceil() as usize)
+            .min(num_samples)
+            .max(start + 1);
+        (start, end)
+    }
+
+    /// the sample under a horizontal position in a view `width` wide
+    fn sample_at(&self, x: f64, width: f64, num_samples: usize) -> usize {
+        let (start, end) = self.visible_samples(num_samples);
+        let x = (x / width).max(0.0).min(1.0);
+        start + (x * ((end - start) as f64)).round() as usize
+    }
+}
+
+/// A view of the waveform that can be zoomed with the scroll wheel, down to individual samples.
+pub struct WaveformView {
+    /// the position of the mouse, if it is over the view
+    hover: Option<Point>,
+    /// the sample where the current selection drag started
+    drag_anchor: Option<usize>,
+}
+
+impl WaveformView {
+    pub fn new() -> Self {
+        Self {
+            hover: None,
+            drag_anchor: None,
+        }
+    }
+
+    /// draw each sample as a point, and read out the value of the sample under the mouse
+    fn paint_samples(
+        &self,
+        ctx: &mut druid::PaintCtx<'_, '_, '_>,
+        file: &AudioFile,
+        start: usize,
+        end: usize,
+    ) {
+        let size = ctx.size();
+        let lane_height = size.height / (file.num_channels as f64);
+        let sample_width = size.width / ((end - start) as f64);
+        let center = |ch: usize, idx: usize| {
+            let value = file.get_channel(ch)[idx] as f64;
+            Point::new(
+                sample_width * ((idx - start) as f64 + 0.5),
+                lane_height * (ch as f64 + 0.5 - 0.5 * value),
+            )
+        };
+
+        if sample_width >= 4.0 {
+            for ch in 0..file.num_channels {
+                for idx in start..end {
+                    ctx.fill(Circle::new(center(ch, idx), 2.0), &Color::WHITE);
+                }
+            }
+        }
+
+        let pos = if let Some(pos) = self.hover {
+            pos
+        } else {
+            return;
+        };
+        let idx = start + (pos.x / sample_width).max(0.0) as usize;
+        let ch = ((pos.y / lane_height).max(0.0) as usize).min(file.num_channels - 1);
+        if idx >= end {
+            return;
+        }
+        ctx.stroke(
+            Circle::new(center(ch, idx), 4.0),
+            &Color::rgb8(0xff, 0xc0, 0x40),
+            1.5,
+        );
+        let text = format!(
+            "channel {} | sample {} | {:+.6}",
+            ch,
+            idx,
+            file.get_channel(ch)[idx]
+        );
+        if let Ok(layout) = ctx
+            .text()
+            .new_text_layout(text)
+            .font(FontFamily::SYSTEM_UI, 11.0)
+            .text_color(Color::WHITE)
+            .build()
+        {
+            ctx.draw_text(&layout, (4.0, 2.0));
+        }
+    }
 }
 
-pub struct WaveformView;
 impl Widget<UiData> for WaveformView {
     fn event(
         &mut self,
-        _ctx: &mut druid::EventCtx,
+        ctx: &mut druid::EventCtx,
         event: &druid::Event,
         data: &mut UiData,
         _env: &druid::Env,
     ) {
-        if let druid::Event::Command(cmd) = event {
-            // todo: avoid crashes here
-            let mut guard = data.peaks.lock().expect("mutex poisoned");
-            let peaks = &mut *guard;
-            if let Some(size) = cmd.get::<druid::Size>(druid::Selector::new("SCALE_WAVEFORMS")) {
-                println!("resizing waveforms: {:?}", size);
-                let guard = data.controller.lock().expect("mutex poisoned");
-                let file = guard.file.as_ref().expect("no file");
-                for ch in 0..file.num_channels {
-                    for (_, (path, _)) in &mut peaks[ch].peaks {
-                        path.apply_affine(Affine::translate((0.0, 0.5 + ch as f64)));
-                        path.apply_affine(Affine::scale_non_uniform(size.width, size.height / 2.0));
+        match event {
+            druid::Event::Wheel(mouse) => {
+                let num_samples = if let Ok(controller) = data.controller.try_lock() {
+                    controller.duration_samples()
+                } else {
+                    None
+                };
+                if let Some(num_samples) = num_samples {
+                    let width = ctx.size().width;
+                    let factor = 1.0025f64.powf(mouse.wheel_delta.y);
+                    data.view.zoom(factor, mouse.pos.x / width, num_samples);
+                    data.view.pan(mouse.wheel_delta.x / width);
+                    ctx.request_paint();
+                    ctx.set_handled();
+                }
+            }
+            druid::Event::MouseDown(mouse) if mouse.button.is_left() => {
+                if let Some(num_samples) = data
+                    .controller
+                    .try_lock()
+                    .ok()
+                    .and_then(|c| c.duration_samples())
+                {
+                    let sample = data
+                        .view
+                        .sample_at(mouse.pos.x, ctx.size().width, num_samples);
+                    self.drag_anchor = Some(sample);
+                    data.selection = None;
+                    ctx.set_active(true);
+                    ctx.request_paint();
+                }
+            }
+            druid::Event::MouseMove(mouse) => {
+                self.hover = Some(mouse.pos);
+                if let (true, Some(anchor)) = (ctx.is_active(), self.drag_anchor) {
+                    if let Some(num_samples) = data
+                        .controller
+                        .try_lock()
+                        .ok()
+                        .and_then(|c| c.duration_samples())
+                    {
+                        let sample =
+                            data.view
+                                .sample_at(mouse.pos.x, ctx.size().width, num_samples);
+                        data.selection = Some((anchor.min(sample), anchor.max(sample)));
+                    }
+                }
+                ctx.request_paint();
+            }
+            druid::Event::MouseUp(mouse) if mouse.button.is_left() => {
+                if ctx.is_active() {
+                    ctx.set_active(false);
+                    self.drag_anchor = None;
+                    if let Some((start, end)) = data.selection {
+                        if start == end {
+                            data.selection = None;
+                        }
                     }
+                    ctx.request_paint();
                 }
             }
+            _ => (),
         }
     }
 
     fn update(
         &mut self,
-        _ctx: &mut druid::UpdateCtx<'_, '_>,
-        _old_data: &UiData,
-        _data: &UiData,
+        ctx: &mut druid::UpdateCtx<'_, '_>,
+        old_data: &UiData,
+        data: &UiData,
         _env: &druid::Env,
     ) {
+        if !old_data.same(data) {
+            ctx.request_paint();
+        }
     }
 
     fn lifecycle(
         &mut self,
-        _ctx: &mut druid::LifeCycleCtx<'_, '_>,
-        _event: &druid::LifeCycle,
+        ctx: &mut druid::LifeCycleCtx<'_, '_>,
+        event: &druid::LifeCycle,
         _data: &UiData,
         _env: &druid::Env,
     ) {
+        if let druid::LifeCycle::HotChanged(false) = event {
+            self.hover = None;
+            ctx.request_paint();
+        }
     }
 
     fn layout(
         &mut self,
-        ctx: &mut druid::LayoutCtx<'_, '_>,
+        _ctx: &mut druid::LayoutCtx<'_, '_>,
         bc: &druid::BoxConstraints,
         _data: &UiData,
         _env: &druid::Env,
     ) -> druid::Size {
-        let size = bc.max();
-        ctx.submit_command(druid::Command::new(
-            druid::Selector::new("SCALE_WAVEFORMS"),
-            size.clone(),
-            druid::Target::Auto,
-        ));
         bc.max()
     }
 
@@ -291,22 +1394,236 @@ impl Widget<UiData> for WaveformView {
             return;
         };
         let size = ctx.size();
-        let len = file.num_samples as f64;
-        let guard = data.peaks.try_lock().unwrap();
-        let peaks = &*guard;
-        for ch in 0..file.num_channels {
-            let (_, (path, gradient)) = &peaks[ch].peaks[3];
-            //let mut path = path.clone();
-            //path.apply_affine(Affine::translate((0.0, 0.5 + ch as f64)));
-            //path.apply_affine(Affine::scale_non_uniform(size.width, h / 2.0));
-            ctx.stroke(&path, &druid::Color::BLACK, 1.0);
-            ctx.fill(&path, gradient);
-        }
-        let x = (controller.playhead() as f64) / len;
-        let mut cursor = BezPath::new();
-        cursor.move_to((x, 0.0));
-        cursor.line_to((x, 1.0));
-        cursor.apply_affine(Affine::scale_non_uniform(size.width, size.height));
-        ctx.stroke(&cursor, &druid::Color::WHITE, 1.0);
+        let (start, end) = data.view.visible_samples(file.num_samples);
+        if start == 0 && end == file.num_samples {
+            let guard = data.peaks.try_lock().unwrap();
+            let peaks = &*guard;
+            for ch in 0..file.num_channels {
+                // the overview of a file with more channels may not have been computed yet
+                let (_, (path, gradient)) =
+                    if let Some(overview) = peaks.get(ch).and_then(|peaks| peaks.peaks.get(3)) {
+                        overview
+                    } else {
+                        continue;
+                    };
+                let mut path = path.clone();
+                path.apply_affine(
+                    Affine::scale_non_uniform(size.width, size.height / 2.0)
+                        * Affine::translate((0.0, 0.5 + ch as f64)),
+                );
+                ctx.stroke(&path, &druid::Color::BLACK, 1.0);
+                ctx.fill(&path, gradient);
+            }
+        } else {
+            let lane_height = size.height / (file.num_channels as f64);
+            let step_size = ((((end - start) as f64) / size.width) as usize).max(1);
+            for ch in 0..file.num_channels {
+                let (mut peak, mut avg) = file.plot(step_size, ch, start, end);
+                let transform = Affine::translate((0.0, lane_height * ch as f64))
+                    * Affine::scale_non_uniform(size.width, lane_height);
+                peak.apply_affine(transform);
+                avg.apply_affine(transform);
+                if step_size == 1 {
+                    ctx.stroke(&peak, &Color::grey(0.8), 1.0);
+                } else {
+                    ctx.fill(&peak, &Color::grey(0.5));
+                    ctx.fill(&avg, &Color::grey(0.8));
+                }
+            }
+            if step_size == 1 {
+                self.paint_samples(ctx, file, start, end);
+            }
+        }
+        let to_x = |sample: usize| ((sample as f64) - (start as f64)) / ((end - start) as f64);
+        if let Some((sel_start, sel_end)) = data.selection {
+            let x0 = to_x(sel_start).max(0.0).min(1.0) * size.width;
+            let x1 = to_x(sel_end).max(0.0).min(1.0) * size.width;
+            let selection = Rect::new(x0, 0.0, x1, size.height);
+            ctx.fill(selection, &Color::rgba8(0xff, 0xff, 0xff, 0x30));
+        }
+        let x = to_x(data.heard_playhead(file.sample_rate));
+        if (0.0..=1.0).contains(&x) {
+            let mut cursor = BezPath::new();
+            cursor.move_to((x, 0.0));
+            cursor.line_to((x, 1.0));
+            cursor.apply_affine(Affine::scale_non_uniform(size.width, size.height));
+            ctx.stroke(&cursor, &druid::Color::WHITE, 1.0);
+        }
+    }
+}
+
+/// the quietest level shown on the meters, in decibels
+const METER_FLOOR: f32 = -60.0;
+
+/// Level meters for every output channel, drawn as an RMS bar with a line at the peak level
+/// and a marker at the held peak
+struct LevelMeterView {
+    levels: Vec<ChannelLevel>,
+}
+
+impl LevelMeterView {
+    fn new() -> Self {
+        Self { levels: vec![] }
+    }
+}
+
+/// the position of a linear gain on a meter, between 0 and 1
+fn meter_position(gain: f32) -> f64 {
+    let db = 20.0 * gain.max(1e-6).log10();
+    ((db - METER_FLOOR) / -METER_FLOOR).max(0.0).min(1.0) as f64
+}
+
+impl Widget<UiData> for LevelMeterView {
+    fn event(
+        &mut self,
+        ctx: &mut druid::EventCtx,
+        event: &druid::Event,
+        data: &mut UiData,
+        _env: &druid::Env,
+    ) {
+        if let druid::Event::AnimFrame(nanos) = event {
+            if let Ok(mut meter) = data.meter.try_lock() {
+                meter.update((*nanos as f64) * 1e-9);
+                self.levels.clear();
+                self.levels.extend_from_slice(meter.levels());
+            }
+            ctx.request_paint();
+            ctx.request_anim_frame();
+        }
+    }
+
+    fn update(
+        &mut self,
+        _ctx: &mut druid::UpdateCtx<'_, '_>,
+        _old_data: &UiData,
+        _data: &UiData,
+        _env: &druid::Env,
+    ) {
+    }
+
+    fn lifecycle(
+        &mut self,
+        ctx: &mut druid::LifeCycleCtx<'_, '_>,
+        event: &druid::LifeCycle,
+        _data: &UiData,
+        _env: &druid::Env,
+    ) {
+        if let druid::LifeCycle::WidgetAdded = event {
+            ctx.request_anim_frame();
+        }
+    }
+
+    fn layout(
+        &mut self,
+        _ctx: &mut druid::LayoutCtx<'_, '_>,
+        bc: &druid::BoxConstraints,
+        _data: &UiData,
+        _env: &druid::Env,
+    ) -> druid::Size {
+        bc.max()
+    }
+
+    fn paint(&mut self, ctx: &mut druid::PaintCtx<'_, '_, '_>, _data: &UiData, env: &druid::Env) {
+        let size = ctx.size();
+        ctx.fill(size.to_rect(), &env.get(druid::theme::BACKGROUND_DARK));
+        if self.levels.is_empty() {
+            return;
+        }
+        let height = size.height / (self.levels.len() as f64);
+        let labels = ChannelLayout::from_channels(self.levels.len()).labels();
+        for ((channel, level), label) in self.levels.iter().enumerate().zip(labels) {
+            let top = height * (channel as f64) + 1.0;
+            let bottom = top + height - 2.0;
+            let rms = meter_position(level.rms) * size.width;
+            ctx.fill(
+                Rect::new(0.0, top, rms, bottom),
+                &Color::rgb8(0x40, 0xa0, 0x40),
+            );
+            let peak = meter_position(level.peak) * size.width;
+            ctx.fill(
+                Rect::new(rms, top, peak.max(rms), bottom),
+                &Color::rgb8(0x30, 0x60, 0x30),
+            );
+            let hold = meter_position(level.hold) * size.width;
+            let color = if level.hold >= 1.0 {
+                Color::rgb8(0xe0, 0x30, 0x30)
+            } else {
+                Color::WHITE
+            };
+            ctx.fill(Rect::new(hold - 2.0, top, hold, bottom), &color);
+            if let Ok(layout) = ctx
+                .text()
+                .new_text_layout(label)
+                .font(FontFamily::SYSTEM_UI, (height - 2.0).clamp(1.0, 11.0))
+                .text_color(Color::WHITE)
+                .build()
+            {
+                ctx.draw_text(&layout, (4.0, top));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_stream::offline_stream;
+    use crate::sample_player::sample_player;
+
+    const BLOCK_SIZE: usize = 64;
+    const NUM_SAMPLES: usize = 4800;
+
+    #[test]
+    fn actions_drive_the_player() {
+        let path = std::env::temp_dir().join(format!("play-ui-{}.wav", std::process::id()));
+        AudioFile {
+            data: vec![0.25; 2 * NUM_SAMPLES],
+            sample_rate: 48000.0,
+            num_channels: 2,
+            num_samples: NUM_SAMPLES,
+        }
+        .save(&path)
+        .unwrap();
+
+        let gc = Collector::new();
+        let (mut player, mut controller) = sample_player(&gc);
+        controller.set_fade_time(0.0);
+        let mut data = UiData::new(Arc::new(Mutex::new(controller)), StreamHandle::detached());
+        let mut stream = offline_stream(48000.0, BLOCK_SIZE, 2, |mut context| {
+            player.advance(&mut context);
+        });
+
+        // load
+        data.open_target = OpenTarget::File;
+        data.open(path.to_str().unwrap());
+        assert_eq!(data.peaks.lock().unwrap().len(), 2);
+        stream.render(BLOCK_SIZE);
+        data.sync();
+        assert!(!data.is_playing);
+        assert_eq!(data.transport.num_samples(), NUM_SAMPLES);
+        assert_eq!(data.play_pos, 0.0);
+
+        // play
+        assert!(data.perform(Action::PlayPause).is_none());
+        stream.render(10 * BLOCK_SIZE);
+        data.sync();
+        assert!(data.is_playing);
+        assert_eq!(data.transport.playhead(), 10 * BLOCK_SIZE);
+        assert_eq!(data.play_pos, (10 * BLOCK_SIZE) as f64 / NUM_SAMPLES as f64);
+
+        // seek back to the start, which keeps playing from there
+        data.perform(Action::SeekStart);
+        stream.render(BLOCK_SIZE);
+        data.sync();
+        assert!(data.is_playing);
+        assert_eq!(data.transport.playhead(), BLOCK_SIZE);
+
+        // stop
+        data.perform(Action::PlayPause);
+        stream.render(BLOCK_SIZE);
+        data.sync();
+        assert!(!data.is_playing);
+
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,101 @@
+use crate::audio_file::AudioFile;
+use basedrop::Shared;
+use std::collections::VecDeque;
+
+/// A state that can be stored in an undo history
+pub trait Undoable {
+    /// the approximate number of bytes held by this state
+    fn memory_usage(&self) -> usize;
+}
+
+impl Undoable for Shared<AudioFile> {
+    fn memory_usage(&self) -> usize {
+        AudioFile::memory_usage(self)
+    }
+}
+
+/// A history of previous states that can be undone and redone. When the history holds more
+/// than `memory_limit` bytes the oldest states are forgotten.
+pub struct UndoStack<T> {
+    undo: VecDeque<T>,
+    redo: Vec<T>,
+    memory_limit: usize,
+}
+
+impl<T: Undoable> UndoStack<T> {
+    /// create an empty history
+    pub fn new(memory_limit: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: vec![],
+            memory_limit,
+        }
+    }
+
+    /// record the state from before an operation, discarding anything that could be redone
+    pub fn push(&mut self, previous: T) {
+        self.redo.clear();
+        self.undo.push_back(previous);
+        self.trim();
+    }
+
+    /// return the state to restore when undoing from `current`, which is `current` itself if
+    /// there is nothing to undo
+    pub fn undo(&mut self, current: T) -> T {
+        if let Some(previous) = self.undo.pop_back() {
+            self.redo.push(current);
+            previous
+        } else {
+            current
+        }
+    }
+
+    /// return the state to restore when redoing from `current`, which is `current` itself if
+    /// there is nothing to redo
+    pub fn redo(&mut self, current: T) -> T {
+        if let Some(next) = self.redo.pop() {
+            self.undo.push_back(current);
+            self.trim();
+            next
+        } else {
+            current
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// forget the whole history
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// change the memory limit, forgetting old states if the history is now too large
+    pub fn set_memory_limit(&mut self, memory_limit: usize) {
+        self.memory_limit = memory_limit;
+        self.trim();
+    }
+
+    /// the approximate number of bytes held by the history
+    pub fn memory_usage(&self) -> usize {
+        self.undo
+            .iter()
+            .chain(self.redo.iter())
+            .map(Undoable::memory_usage)
+            .sum()
+    }
+
+    /// drop the oldest undo states until the history fits in the memory limit, always keeping
+    /// the most recent one
+    fn trim(&mut self) {
+        while self.undo.len() > 1 && self.memory_usage() > self.memory_limit {
+            self.undo.pop_front();
+        }
+    }
+}
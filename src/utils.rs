@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
@@ -57,3 +58,109 @@ pub fn deinterleave<T: Copy>(input: &[T], output: &mut [T], num_channels: usize)
         }
     }
 }
+
+/// convert a gain in decibels to a linear gain factor
+pub fn db_to_gain(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+/// A linear gain that ramps towards its target over the course of one block, to
+/// avoid clicks when the gain changes.
+#[derive(Clone, Copy)]
+pub struct SmoothedGain {
+    current: f32,
+    target: f32,
+}
+
+impl SmoothedGain {
+    /// create a new gain, set to `gain` with no ramp pending
+    pub fn new(gain: f32) -> Self {
+        Self {
+            current: gain,
+            target: gain,
+        }
+    }
+
+    /// set the gain that will be ramped towards, in decibels
+    pub fn set_db(&mut self, db: f32) {
+        self.target = db_to_gain(db);
+    }
+
+    /// set the linear gain that will be ramped towards
+    pub fn set(&mut self, gain: f32) {
+        self.target = gain;
+    }
+
+    /// the gain at the start and end of the next block
+    pub fn ramp(&self) -> (f32, f32) {
+        (self.current, self.target)
+    }
+
+    /// finish the current ramp, called once per block
+    pub fn snap(&mut self) {
+        self.current = self.target;
+    }
+}
+
+/// Multiply a buffer of samples by a gain that moves linearly from `start` to `end`
+pub fn apply_gain_ramp(buffer: &mut [f32], start: f32, end: f32) {
+    if start == end {
+        for sample in buffer.iter_mut() {
+            *sample *= start;
+        }
+        return;
+    }
+    let step = (end - start) / (buffer.len() as f32);
+    for (n, sample) in buffer.iter_mut().enumerate() {
+        *sample *= start + step * (n as f32);
+    }
+}
+
+/// Add a buffer of samples into an output buffer, multiplied by a gain that moves linearly
+/// from `start` to `end`
+pub fn mix_with_gain_ramp(input: &[f32], output: &mut [f32], start: f32, end: f32) {
+    debug_assert_eq!(input.len(), output.len());
+    let step = (end - start) / (input.len() as f32);
+    for (n, (sample, out)) in input.iter().zip(output.iter_mut()).enumerate() {
+        *out += sample * (start + step * (n as f32));
+    }
+}
+
+/// Zero a value that has decayed below the smallest normal `f32`. State that is multiplied
+/// from one block to the next can otherwise end up denormal, which is far slower to work with.
+pub fn flush_denormal(value: f32) -> f32 {
+    if value.abs() < f32::MIN_POSITIVE {
+        0.0
+    } else {
+        value
+    }
+}
+
+/// the highest absolute level of a buffer of samples
+pub fn peak(buffer: &[f32]) -> f32 {
+    buffer
+        .iter()
+        .fold(0.0, |peak, sample| peak.max(sample.abs()))
+}
+
+/// the sum of the squares of a buffer of samples, which RMS levels are measured from
+pub fn sum_of_squares(buffer: &[f32]) -> f32 {
+    buffer.iter().map(|sample| sample * sample).sum()
+}
+
+/// the RMS level of a buffer of samples
+pub fn rms(buffer: &[f32]) -> f32 {
+    if buffer.is_empty() {
+        return 0.0;
+    }
+    (sum_of_squares(buffer) / buffer.len() as f32).sqrt()
+}
+
+/// where a config file is stored, in the `play` folder of the user's config directory
+pub fn config_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .map(|config| config.join("play").join(name))
+}